@@ -20,7 +20,7 @@ fn get_git_hash() -> String {
   if let Some(commit) = commit_output.lines().next() {
     format!("{}-{}", branch, commit)
   } else {
-    format!("{}", branch)
+    branch.to_string()
   }
 }
 
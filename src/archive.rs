@@ -0,0 +1,164 @@
+// Archive services, such as the Wayback Machine of the Internet Archive.
+// They are used as a fallback when a page cannot be fetched anymore and,
+// optionally, to archive every new bookmark.
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use reqwest::redirect::Policy;
+use serde::Deserialize;
+
+use crate::{http_client, Config};
+
+pub const INTERNET_ARCHIVE_URL: &str = "https://web.archive.org";
+
+pub trait ArchiveProvider {
+  fn name(&self) -> &str;
+  // Returns the content of the most recent archived copy of the page, None if
+  // the page was never archived
+  fn fetch(&self, url: &str) -> Result<Option<String>>;
+  // Asks the service to archive the page
+  fn submit(&self, url: &str) -> Result<()>;
+}
+
+// The Internet Archive, through its availability and save APIs
+pub struct InternetArchive {
+  base_url: String,
+  client: Client,
+}
+
+#[derive(Deserialize)]
+struct Availability {
+  archived_snapshots: Snapshots,
+}
+
+#[derive(Deserialize)]
+struct Snapshots {
+  closest: Option<Snapshot>,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+  available: bool,
+  timestamp: String,
+}
+
+impl InternetArchive {
+  pub fn new(base_url: &str) -> Result<Self> {
+    Ok(InternetArchive {
+      base_url: base_url.trim_end_matches('/').to_string(),
+      client: http_client(Policy::default())?,
+    })
+  }
+}
+
+impl ArchiveProvider for InternetArchive {
+  fn name(&self) -> &str {
+    "the Internet Archive"
+  }
+
+  fn fetch(&self, url: &str) -> Result<Option<String>> {
+    let response = self
+      .client
+      .get(format!("{}/wayback/available", self.base_url))
+      .query(&[("url", url)])
+      .send()?
+      .error_for_status()?;
+    let availability: Availability = serde_json::from_str(&response.text()?)
+      .context("unexpected answer from the availability API")?;
+    let Some(snapshot) = availability.archived_snapshots.closest.filter(|s| s.available) else {
+      return Ok(None);
+    };
+    // The id_ suffix asks for the page as it was, without the Wayback Machine
+    // banner and rewritten links
+    let response = self
+      .client
+      .get(format!("{}/web/{}id_/{}", self.base_url, snapshot.timestamp, url))
+      .send()?
+      .error_for_status()?;
+    Ok(Some(response.text()?))
+  }
+
+  fn submit(&self, url: &str) -> Result<()> {
+    self
+      .client
+      .get(format!("{}/save/{}", self.base_url, url))
+      .send()?
+      .error_for_status()?;
+    Ok(())
+  }
+}
+
+// Returns the configured archive provider, None if disabled
+pub fn provider(config: &Config) -> Result<Option<Box<dyn ArchiveProvider>>> {
+  match &config.archive {
+    Some(archive) if archive.enabled => {
+      let base_url = archive.base_url.as_deref().unwrap_or(INTERNET_ARCHIVE_URL);
+      Ok(Some(Box::new(InternetArchive::new(base_url)?)))
+    }
+    _ => Ok(None),
+  }
+}
+
+// Whether every new bookmark is to be submitted to the archive provider
+pub fn submit_enabled(config: &Config) -> bool {
+  config.archive.as_ref().is_some_and(|a| a.enabled && a.submit.unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  // A mock of the Internet Archive which only knows one copy of
+  // https://example.com/page. Returns its url and the requests it received.
+  fn mock_archive() -> (String, Arc<Mutex<Vec<String>>>) {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", server.server_addr());
+    let requests = Arc::new(Mutex::new(vec![]));
+    let received = requests.clone();
+    std::thread::spawn(move || {
+      for request in server.incoming_requests() {
+        let url = request.url().to_string();
+        received.lock().unwrap().push(url.clone());
+        let body = if url == "/wayback/available?url=https%3A%2F%2Fexample.com%2Fpage" {
+          r#"{"archived_snapshots":{"closest":{"available":true,"timestamp":"20240101000000","status":"200"}}}"#
+        } else if url.starts_with("/wayback/available") {
+          r#"{"archived_snapshots":{}}"#
+        } else if url == "/web/20240101000000id_/https://example.com/page" {
+          "<html><body>archived</body></html>"
+        } else if url.starts_with("/save/") {
+          ""
+        } else {
+          let _ = request.respond(tiny_http::Response::empty(404));
+          continue;
+        };
+        let _ = request.respond(tiny_http::Response::from_string(body));
+      }
+    });
+    (base_url, requests)
+  }
+
+  #[test]
+  fn fetches_the_archived_copy() {
+    let (base_url, requests) = mock_archive();
+    let archive = InternetArchive::new(&format!("{}/", base_url)).unwrap();
+    let content = archive.fetch("https://example.com/page").unwrap();
+    assert_eq!(content.as_deref(), Some("<html><body>archived</body></html>"));
+    assert_eq!(archive.fetch("https://example.com/other").unwrap(), None);
+    assert_eq!(
+      *requests.lock().unwrap(),
+      vec![
+        "/wayback/available?url=https%3A%2F%2Fexample.com%2Fpage",
+        "/web/20240101000000id_/https://example.com/page",
+        "/wayback/available?url=https%3A%2F%2Fexample.com%2Fother",
+      ]
+    );
+  }
+
+  #[test]
+  fn submits_the_pages() {
+    let (base_url, requests) = mock_archive();
+    let archive = InternetArchive::new(&base_url).unwrap();
+    archive.submit("https://example.com/new").unwrap();
+    assert_eq!(*requests.lock().unwrap(), vec!["/save/https://example.com/new"]);
+  }
+}
@@ -0,0 +1,150 @@
+// Url normalisation so that the same page reached through different urls
+// (http/https, www., tracking parameters, ...) ends up as a single bookmark.
+use reqwest::Url;
+use scraper::{Html, Selector};
+
+use crate::Config;
+
+// Query parameters stripped when the configuration does not provide a list.
+// A trailing '*' matches any suffix.
+pub const DEFAULT_TRACKING_PARAMS: &[&str] = &[
+  "utm_*", "fbclid", "gclid", "dclid", "msclkid", "yclid", "mc_cid", "mc_eid",
+  "igshid", "_hsenc", "_hsmi", "mkt_tok", "ref_src",
+];
+
+fn is_tracking_param(patterns: &[String], name: &str) -> bool {
+  patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+    Some(prefix) => name.starts_with(prefix),
+    None => name == pattern,
+  })
+}
+
+// Returns the canonical form of a url according to the configuration.
+// Parsing the url already lowercases the scheme and the host and removes the
+// default ports. Urls which cannot be parsed are returned untouched.
+pub fn canonicalize(config: &Config, url: &str) -> String {
+  let Some(canonical_config) = &config.canonicalize else {
+    return url.to_string();
+  };
+  if !canonical_config.enabled {
+    return url.to_string();
+  }
+  let Ok(mut parsed) = Url::parse(url.trim()) else {
+    return url.to_string();
+  };
+  if !matches!(parsed.scheme(), "http" | "https") {
+    return url.to_string();
+  }
+  if canonical_config.https.unwrap_or(false) && parsed.scheme() == "http" {
+    let _ = parsed.set_scheme("https");
+  }
+  if canonical_config.strip_www.unwrap_or(false) {
+    if let Some(host) = parsed.host_str().and_then(|h| h.strip_prefix("www.")) {
+      let host = host.to_string();
+      let _ = parsed.set_host(Some(&host));
+    }
+  }
+  if canonical_config.strip_fragment.unwrap_or(false) {
+    parsed.set_fragment(None);
+  }
+  if canonical_config.strip_trailing_slash.unwrap_or(false) {
+    let path = parsed.path().trim_end_matches('/').to_string();
+    if !path.is_empty() {
+      parsed.set_path(&path);
+    }
+  }
+  let tracking_params = match &canonical_config.tracking_params {
+    Some(params) => params.clone(),
+    None => DEFAULT_TRACKING_PARAMS.iter().map(|p| p.to_string()).collect(),
+  };
+  if parsed.query_pairs().any(|(name, _)| is_tracking_param(&tracking_params, &name)) {
+    let pairs: Vec<(String, String)> = parsed
+      .query_pairs()
+      .filter(|(name, _)| !is_tracking_param(&tracking_params, name))
+      .map(|(name, value)| (name.into_owned(), value.into_owned()))
+      .collect();
+    if pairs.is_empty() {
+      parsed.set_query(None);
+    } else {
+      parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+  }
+  parsed.to_string()
+}
+
+// Returns whether the configuration asks to follow <link rel="canonical">.
+pub fn use_canonical_link(config: &Config) -> bool {
+  config
+    .canonicalize
+    .as_ref()
+    .is_some_and(|c| c.enabled && c.use_canonical_link.unwrap_or(false))
+}
+
+// Looks for a <link rel="canonical"> in the page and returns its absolute url.
+// Relative links are resolved against the url the page was fetched from.
+pub fn canonical_link(page_url: &str, document: &Html) -> Option<String> {
+  let selector = Selector::parse(r#"link[rel="canonical"]"#).unwrap();
+  let href = document.select(&selector).next()?.value().attr("href")?;
+  let base = Url::parse(page_url).ok()?;
+  let link = base.join(href.trim()).ok()?;
+  if matches!(link.scheme(), "http" | "https") {
+    Some(link.to_string())
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::config;
+
+  #[test]
+  fn canonicalizes_the_urls() {
+    let config = config();
+    for (url, expected) in [
+      ("http://WWW.Example.com:80/a/?utm_source=feed&id=3#top", "http://www.example.com/a?id=3"),
+      ("https://example.com/?fbclid=abc&utm_medium=x", "https://example.com/"),
+      ("  https://example.com/a/b//  ", "https://example.com/a/b"),
+      ("https://example.com:8443/a", "https://example.com:8443/a"),
+      ("ftp://www.example.com/a/", "ftp://www.example.com/a/"),
+      ("not a url", "not a url"),
+    ] {
+      assert_eq!(canonicalize(&config, url), expected, "{}", url);
+    }
+  }
+
+  #[test]
+  fn rewrites_the_scheme_and_host_when_configured() {
+    let mut config = config();
+    let canonicalize_config = config.canonicalize.as_mut().unwrap();
+    canonicalize_config.https = Some(true);
+    canonicalize_config.strip_www = Some(true);
+    assert_eq!(canonicalize(&config, "http://www.example.com/a"), "https://example.com/a");
+  }
+
+  #[test]
+  fn keeps_the_urls_when_disabled() {
+    let mut config = config();
+    config.canonicalize.as_mut().unwrap().enabled = false;
+    assert_eq!(canonicalize(&config, "http://www.example.com/a/#top"), "http://www.example.com/a/#top");
+  }
+
+  #[test]
+  fn uses_the_configured_tracking_params() {
+    let mut config = config();
+    config.canonicalize.as_mut().unwrap().tracking_params = Some(vec!["ref".to_string(), "src_*".to_string()]);
+    assert_eq!(
+      canonicalize(&config, "https://example.com/a?ref=x&src_feed=1&utm_source=y"),
+      "https://example.com/a?utm_source=y"
+    );
+  }
+
+  #[test]
+  fn resolves_the_canonical_link() {
+    let document = Html::parse_document(r#"<html><head><link rel="canonical" href="/b"></head></html>"#);
+    assert_eq!(canonical_link("https://example.com/a?id=1", &document).as_deref(), Some("https://example.com/b"));
+    let document = Html::parse_document(r#"<link rel="canonical" href="javascript:alert(1)">"#);
+    assert_eq!(canonical_link("https://example.com/a", &document), None);
+  }
+}
@@ -0,0 +1,88 @@
+// Exporting the bookmarks to formats other tools understand.
+use anyhow::Result;
+use std::io::Write;
+
+use crate::Bookmark;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+  // The Netscape bookmark file format, imported by browsers
+  Html,
+  // A Markdown list of links
+  Markdown,
+}
+
+pub fn escape_html(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn escape_markdown(text: &str) -> String {
+  text.replace('[', "\\[").replace(']', "\\]")
+}
+
+fn title(bookmark: &Bookmark) -> &str {
+  if bookmark.title.is_empty() {
+    &bookmark.href
+  } else {
+    &bookmark.title
+  }
+}
+
+fn html(bookmarks: &[&Bookmark], writer: &mut dyn Write) -> Result<()> {
+  writeln!(writer, "<!DOCTYPE NETSCAPE-Bookmark-file-1>")?;
+  writeln!(writer, "<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">")?;
+  writeln!(writer, "<TITLE>Bookmarks</TITLE>")?;
+  writeln!(writer, "<H1>Bookmarks</H1>")?;
+  writeln!(writer, "<DL><p>")?;
+  for bookmark in bookmarks {
+    write!(writer, "    <DT><A HREF=\"{}\"", escape_html(&bookmark.href))?;
+    if let Some(posted) = bookmark.meta.posted {
+      write!(writer, " ADD_DATE=\"{}\"", posted.and_utc().timestamp())?;
+    }
+    if !bookmark.meta.tags.is_empty() {
+      write!(writer, " TAGS=\"{}\"", escape_html(&bookmark.meta.tags.join(",")))?;
+    }
+    writeln!(writer, ">{}</A>", escape_html(title(bookmark)))?;
+    // The description, shown by some browsers
+    let mut description: Vec<String> = bookmark.meta.notes.iter().cloned().collect();
+    description.extend(bookmark.meta.highlights.iter().map(|h| format!("\u{201c}{}\u{201d}", h.text)));
+    if !description.is_empty() {
+      writeln!(writer, "    <DD>{}", escape_html(&description.join("\n")))?;
+    }
+  }
+  writeln!(writer, "</DL><p>")?;
+  Ok(())
+}
+
+fn markdown(bookmarks: &[&Bookmark], writer: &mut dyn Write) -> Result<()> {
+  for bookmark in bookmarks {
+    write!(writer, "- [{}]({})", escape_markdown(title(bookmark)), bookmark.href)?;
+    if !bookmark.meta.tags.is_empty() {
+      write!(writer, " {}", bookmark.meta.tags.iter().map(|t| format!("#{}", t)).collect::<Vec<_>>().join(" "))?;
+    }
+    writeln!(writer)?;
+    if let Some(notes) = &bookmark.meta.notes {
+      writeln!(writer)?;
+      for line in notes.lines() {
+        writeln!(writer, "{}", if line.is_empty() { String::new() } else { format!("  {}", line) })?;
+      }
+    }
+    for highlight in &bookmark.meta.highlights {
+      writeln!(writer)?;
+      writeln!(writer, "  > {}", highlight.text)?;
+    }
+  }
+  Ok(())
+}
+
+// Writes the bookmarks in the given format.
+pub fn export(format: Format, bookmarks: &[&Bookmark], writer: &mut dyn Write) -> Result<()> {
+  match format {
+    Format::Html => html(bookmarks, writer),
+    Format::Markdown => markdown(bookmarks, writer),
+  }
+}
@@ -0,0 +1,179 @@
+// Cleaning of the data folder: the directories belonging to no bookmark, left by
+// hand edits of the bookmark file or url changes, and the stored copies which
+// are empty, truncated or unreadable.
+use anyhow::Result;
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::layout::{self, EMBEDDINGS, PAGE};
+use crate::stats::human_size;
+use crate::{Bookmark, UrlStore};
+
+// The end of the stored pages searched for their closing tags
+const TAIL_SIZE: u64 = 1024;
+
+#[derive(PartialEq)]
+enum Problem {
+  // Belongs to no bookmark
+  Orphan,
+  Empty,
+  // A page which does not end with its closing tags
+  Truncated,
+  // Embeddings which cannot be read
+  Corrupted,
+}
+
+impl Problem {
+  fn name(&self) -> &'static str {
+    match self {
+      Problem::Orphan => "orphan",
+      Problem::Empty => "empty",
+      Problem::Truncated => "truncated",
+      Problem::Corrupted => "corrupted",
+    }
+  }
+}
+
+struct Garbage {
+  path: PathBuf,
+  problem: Problem,
+  size: u64,
+}
+
+// Whether the page ends with </html> or </body>, possibly followed by some
+// trailing content. Only the HTML documents are checked, the other content
+// stored (plain text, PDF...) has no such end.
+fn is_complete(path: &Path) -> Result<bool> {
+  let mut file = std::fs::File::open(path)?;
+  let mut head = vec![];
+  (&mut file).take(TAIL_SIZE).read_to_end(&mut head)?;
+  let head = String::from_utf8_lossy(&head).to_lowercase();
+  if !head.contains("<html") && !head.contains("<!doctype html") {
+    return Ok(true);
+  }
+  let length = file.metadata()?.len();
+  file.seek(SeekFrom::Start(length.saturating_sub(TAIL_SIZE)))?;
+  let mut tail = vec![];
+  file.read_to_end(&mut tail)?;
+  let tail = String::from_utf8_lossy(&tail).to_lowercase();
+  Ok(tail.contains("</html") || tail.contains("</body"))
+}
+
+fn is_readable_embeddings(path: &Path) -> bool {
+  std::fs::read(path).is_ok_and(|content| layout::decode_embeddings(&content).is_ok())
+}
+
+fn file_size(path: &Path) -> u64 {
+  std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+fn collect(url_store: &UrlStore, bookmarks: &[Bookmark]) -> Result<Vec<Garbage>> {
+  let hashes: HashSet<&str> = bookmarks.iter().map(|b| b.hash.as_str()).collect();
+  let mut garbage = vec![];
+  for entry in layout::entries(&url_store.data_folder)? {
+    if !hashes.contains(entry.hash.as_str()) {
+      garbage.push(Garbage { path: entry.path, problem: Problem::Orphan, size: entry.size });
+      continue;
+    }
+    let page = entry.path.join(PAGE);
+    if page.exists() {
+      let size = file_size(&page);
+      if size == 0 {
+        garbage.push(Garbage { path: page, problem: Problem::Empty, size });
+      } else if !is_complete(&page)? {
+        garbage.push(Garbage { path: page, problem: Problem::Truncated, size });
+      }
+    }
+    let embeddings = entry.path.join(EMBEDDINGS);
+    if embeddings.exists() && !is_readable_embeddings(&embeddings) {
+      let size = file_size(&embeddings);
+      let problem = if size == 0 { Problem::Empty } else { Problem::Corrupted };
+      garbage.push(Garbage { path: embeddings, problem, size });
+    }
+  }
+  Ok(garbage)
+}
+
+// Deletes a whole directory, or a file of the directory of a bookmark
+fn delete(item: &Garbage) -> Result<()> {
+  if item.problem == Problem::Orphan {
+    return layout::remove_entry(&item.path);
+  }
+  match (item.path.parent(), item.path.file_name()) {
+    (Some(dir), Some(name)) => layout::remove_file(dir, &name.to_string_lossy()),
+    _ => Ok(()),
+  }
+}
+
+// Lists the garbage of the data folder or, with delete, deletes it. The
+// truncated pages are only reported, as they may be the only copy left of a
+// page: `bookmark refresh` fetches them again.
+pub fn run(url_store: &UrlStore, bookmarks: &[Bookmark], delete_files: bool) -> Result<()> {
+  // The other bookmark files may use the shared folder, their files would look
+  // like orphans
+  let shared = crate::get_shared_data_folder()?;
+  if delete_files && std::path::absolute(&shared)? == std::path::absolute(&url_store.data_folder)? {
+    anyhow::bail!(
+      "{} may be the data folder of other bookmark files, run without --delete to list its garbage",
+      shared.display()
+    );
+  }
+  let garbage = collect(url_store, bookmarks)?;
+  let mut deleted = 0;
+  let mut freed = 0;
+  for item in &garbage {
+    println!("{:10} {:>10}  {}", item.problem.name(), human_size(item.size), item.path.display());
+    if delete_files && item.problem != Problem::Truncated {
+      match delete(item) {
+        Ok(()) => {
+          deleted += 1;
+          freed += item.size;
+        }
+        Err(e) => eprintln!("error: could not delete {} ({})", item.path.display(), e),
+      }
+    }
+  }
+  // The embeddings kept in the SQLite database
+  if let Some(database) = &url_store.database {
+    for hash in database.orphan_embeddings()? {
+      println!("{:10} {:>10}  embeddings of {} in {}", "orphan", "", hash, url_store.config.bookmarks.display());
+      if delete_files {
+        database.delete_embeddings(&hash)?;
+        deleted += 1;
+      }
+    }
+  }
+  if delete_files {
+    println!("deleted {} files ({})", deleted, human_size(freed));
+    if garbage.iter().any(|item| matches!(item.problem, Problem::Empty | Problem::Corrupted)) {
+      println!("run `bookmark check` to fetch the deleted pages and compute the embeddings again");
+    }
+  } else if garbage.iter().any(|item| item.problem != Problem::Truncated) {
+    println!("run with --delete to delete them, except the truncated pages");
+  }
+  if garbage.iter().any(|item| item.problem == Problem::Truncated) {
+    println!("run `bookmark refresh <HASH>` to fetch the truncated pages again");
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_the_html_pages_can_be_truncated() {
+    let dir = tempfile::tempdir().unwrap();
+    let check = |content: &str| {
+      let path = dir.path().join(PAGE);
+      std::fs::write(&path, content).unwrap();
+      is_complete(&path).unwrap()
+    };
+    assert!(check("<!DOCTYPE html><html><body><p>text</p></body></html>\n"));
+    assert!(check("<html><body><p>text</p></body>"));
+    assert!(!check("<!DOCTYPE html><html><body><p>tex"));
+    assert!(check("some plain text"));
+    assert!(check("%PDF-1.7 ..."));
+  }
+}
@@ -0,0 +1,278 @@
+// Keeps the history of the bookmark file in the git repository it lives in
+// and synchronizes it with a remote.
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use crate::storage::{self, BookmarkStore};
+use crate::merge;
+use crate::{Bookmark, Config};
+
+// The directory of the bookmark file, where git is run from
+fn work_dir(config: &Config) -> PathBuf {
+  match config.bookmarks.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+    _ => PathBuf::from("."),
+  }
+}
+
+// Runs git in the directory of the bookmark file
+fn run(config: &Config, args: &[&str]) -> Result<Output> {
+  Command::new("git")
+    .arg("-C")
+    .arg(work_dir(config))
+    .args(args)
+    .output()
+    .context("could not spawn git")
+}
+
+// Runs git and fails if git does
+fn git(config: &Config, args: &[&str]) -> Result<String> {
+  let output = run(config, args)?;
+  if !output.status.success() {
+    anyhow::bail!(
+      "git {} failed: {}",
+      args.join(" "),
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn file_name(config: &Config) -> String {
+  config
+    .bookmarks
+    .file_name()
+    .unwrap_or_default()
+    .to_string_lossy()
+    .to_string()
+}
+
+pub fn enabled(config: &Config) -> bool {
+  config.git.as_ref().is_some_and(|g| g.enabled)
+}
+
+fn remote(config: &Config) -> String {
+  config
+    .git
+    .as_ref()
+    .and_then(|g| g.remote.clone())
+    .unwrap_or("origin".to_string())
+}
+
+fn branch(config: &Config) -> Result<String> {
+  match config.git.as_ref().and_then(|g| g.branch.clone()) {
+    Some(branch) => Ok(branch),
+    None => git(config, &["rev-parse", "--abbrev-ref", "HEAD"]),
+  }
+}
+
+// Commits the bookmark file if it changed.
+pub fn commit(config: &Config, message: &str) -> Result<()> {
+  let file = file_name(config);
+  git(config, &["add", "--", &file])?;
+  // Nothing to commit
+  if run(config, &["diff", "--cached", "--quiet", "--", &file])?.status.success() {
+    return Ok(());
+  }
+  git(config, &["commit", "--quiet", "-m", message, "--", &file])?;
+  Ok(())
+}
+
+// Returns the bookmarks of the bookmark file at a given revision, None if the
+// file does not exist at that revision.
+pub fn show(config: &Config, revision: &str) -> Result<Option<Vec<Bookmark>>> {
+  let prefix = git(config, &["rev-parse", "--show-prefix"])?;
+  let output = run(config, &["show", &format!("{}:{}{}", revision, prefix, file_name(config))])?;
+  if !output.status.success() {
+    return Ok(None);
+  }
+  // The stores read from files, the revision is copied to a temporary one
+  // with the same extension so that the format is the same.
+  let extension = config.bookmarks.extension().unwrap_or_default().to_string_lossy();
+  let mut file = tempfile::Builder::new().prefix("bookmark-").suffix(&format!(".{}", extension)).tempfile()?;
+  file.write_all(&output.stdout)?;
+  file.flush()?;
+  let bookmarks = storage::open_store(file.path(), storage::format(config), 0)?.load()?;
+  Ok(Some(bookmarks))
+}
+
+fn is_ancestor(config: &Config, ancestor: &str, descendant: &str) -> Result<bool> {
+  Ok(run(config, &["merge-base", "--is-ancestor", ancestor, descendant])?.status.success())
+}
+
+// Pulls the remote changes, merges the bookmarks with a three-way merge (see
+// merge.rs) and pushes the result.
+// Git only sees lines, which does not work for a bookmark file, so the merge
+// is done on the bookmarks and git is only told about the result.
+pub fn sync(config: &Config) -> Result<()> {
+  let remote = remote(config);
+  let branch = branch(config)?;
+  let upstream = format!("{}/{}", remote, branch);
+  commit(config, "commit local changes")?;
+  git(config, &["fetch", "--quiet", &remote])?;
+  let has_upstream = run(config, &["rev-parse", "--verify", "--quiet", &upstream])?.status.success();
+  if has_upstream && !is_ancestor(config, &upstream, "HEAD")? {
+    if is_ancestor(config, "HEAD", &upstream)? {
+      git(config, &["merge", "--quiet", "--ff-only", &upstream])?;
+      println!("fast-forwarded to {}", upstream);
+    } else {
+      let merge_base = git(config, &["merge-base", "HEAD", &upstream])?;
+      let base = show(config, &merge_base)?.unwrap_or_default();
+      let ours = storage::load_bookmarks(config)?;
+      let theirs = show(config, &upstream)?.unwrap_or_default();
+      // Let git merge the rest of the repository. The bookmark file may
+      // conflict, its content is replaced anyway.
+      let output = run(config, &["merge", "--quiet", "--no-commit", "--no-ff", &upstream])?;
+      // Git stops on the conflicts with the merge in progress, it refuses to
+      // start it at all when local changes or untracked files are in the way
+      let in_progress = run(config, &["rev-parse", "--verify", "--quiet", "MERGE_HEAD"])?.status.success();
+      if !output.status.success() && !in_progress {
+        anyhow::bail!(
+          "could not merge {}: {}",
+          upstream,
+          String::from_utf8_lossy(&output.stderr).trim()
+        );
+      }
+      let file = file_name(config);
+      let conflicts = git(config, &["diff", "--name-only", "--diff-filter=U", "--relative"])?;
+      if conflicts.lines().any(|path| Path::new(path) != Path::new(&file)) {
+        let _ = run(config, &["merge", "--abort"]);
+        anyhow::bail!("could not merge {}, other files conflict:\n{}", upstream, conflicts);
+      }
+      let merged = merge::merge(&base, &ours, &theirs);
+      for conflict in &merged.conflicts {
+        eprintln!("conflict: {}", conflict);
+      }
+      storage::write_bookmarks(config, &merged.bookmarks)?;
+      git(config, &["add", "--", &file])?;
+      git(config, &["commit", "--quiet", "--no-edit", "-m", &format!("sync: merge {}", upstream)])?;
+      println!("merged {} ({} bookmarks)", upstream, merged.bookmarks.len());
+    }
+  }
+  git(config, &["push", "--quiet", &remote, &format!("HEAD:{}", branch)])?;
+  println!("pushed to {}", upstream);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::bookmark;
+
+  fn git_in(dir: &Path, args: &[&str]) {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output().unwrap();
+    assert!(output.status.success(), "git {}: {}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+  }
+
+  fn config(clone: &Path) -> Config {
+    let mut config = crate::tests::config();
+    config.bookmarks = clone.join("bookmarks.json");
+    config.git = Some(crate::GitConfig { enabled: true, remote: None, branch: Some("main".to_string()) });
+    config
+  }
+
+  // A bare remote and two clones of it sharing a bookmark file
+  fn setup(bookmarks: &[Bookmark]) -> (tempfile::TempDir, Config, Config) {
+    let dir = tempfile::tempdir().unwrap();
+    let remote = dir.path().join("remote.git").to_string_lossy().to_string();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    git_in(dir.path(), &["init", "--quiet", "--bare", &remote]);
+    for clone in [&a, &b] {
+      git_in(dir.path(), &["clone", "--quiet", &remote, &clone.to_string_lossy()]);
+      git_in(clone, &["symbolic-ref", "HEAD", "refs/heads/main"]);
+      git_in(clone, &["config", "user.name", "test"]);
+      git_in(clone, &["config", "user.email", "test@example.com"]);
+    }
+    storage::write_bookmarks(&config(&a), bookmarks).unwrap();
+    commit(&config(&a), "initial bookmarks").unwrap();
+    git_in(&a, &["push", "--quiet", "origin", "main"]);
+    git_in(&b, &["pull", "--quiet", "origin", "main"]);
+    (dir, config(&a), config(&b))
+  }
+
+  fn hrefs(config: &Config) -> Vec<String> {
+    let mut hrefs: Vec<String> = storage::load_bookmarks(config).unwrap().into_iter().map(|b| b.href).collect();
+    hrefs.sort();
+    hrefs
+  }
+
+  fn change(config: &Config, change: impl FnOnce(&mut Vec<Bookmark>)) {
+    let mut bookmarks = storage::load_bookmarks(config).unwrap();
+    change(&mut bookmarks);
+    storage::write_bookmarks(config, &bookmarks).unwrap();
+    commit(config, "change").unwrap();
+  }
+
+  #[test]
+  fn sync_merges_both_sides() {
+    let (_dir, a, b) = setup(&[bookmark("https://example.com/1", "2024-01-01")]);
+    change(&a, |bookmarks| bookmarks.push(bookmark("https://example.com/a", "2024-01-02")));
+    change(&b, |bookmarks| bookmarks.push(bookmark("https://example.com/b", "2024-01-03")));
+    sync(&a).unwrap();
+    sync(&b).unwrap();
+    sync(&a).unwrap();
+    let expected = vec!["https://example.com/1", "https://example.com/a", "https://example.com/b"];
+    assert_eq!(hrefs(&a), expected);
+    assert_eq!(hrefs(&b), expected);
+  }
+
+  #[test]
+  fn sync_keeps_the_remote_deletions() {
+    let (_dir, a, b) = setup(&[
+      bookmark("https://example.com/1", "2024-01-01"),
+      bookmark("https://example.com/2", "2024-01-02"),
+    ]);
+    change(&a, |bookmarks| bookmarks.retain(|b| b.href != "https://example.com/1"));
+    sync(&a).unwrap();
+    change(&b, |bookmarks| bookmarks.push(bookmark("https://example.com/3", "2024-01-03")));
+    sync(&b).unwrap();
+    sync(&a).unwrap();
+    let expected = vec!["https://example.com/2", "https://example.com/3"];
+    assert_eq!(hrefs(&b), expected);
+    assert_eq!(hrefs(&a), expected);
+  }
+
+  #[test]
+  fn sync_keeps_our_title_on_conflicts() {
+    let (_dir, a, b) = setup(&[bookmark("https://example.com/1", "2024-01-01")]);
+    change(&a, |bookmarks| bookmarks[0].title = "Title of a".to_string());
+    sync(&a).unwrap();
+    change(&b, |bookmarks| {
+      bookmarks[0].title = "Title of b".to_string();
+      bookmarks[0].meta.tags.push("b".to_string());
+    });
+    sync(&b).unwrap();
+    let merged = storage::load_bookmarks(&b).unwrap();
+    assert_eq!(merged.len(), 1);
+    assert_eq!(merged[0].title, "Title of b");
+    assert_eq!(merged[0].meta.tags, vec!["b"]);
+    // The merge was pushed
+    sync(&a).unwrap();
+    assert_eq!(storage::load_bookmarks(&a).unwrap()[0].title, "Title of b");
+  }
+
+  #[test]
+  fn sync_fails_when_git_refuses_to_merge() {
+    let (_dir, a, b) = setup(&[bookmark("https://example.com/1", "2024-01-01")]);
+    let (dir_a, dir_b) = (work_dir(&a), work_dir(&b));
+    std::fs::write(dir_a.join("notes.txt"), "from a").unwrap();
+    git_in(&dir_a, &["add", "notes.txt"]);
+    git_in(&dir_a, &["commit", "--quiet", "-m", "notes"]);
+    change(&a, |bookmarks| bookmarks.push(bookmark("https://example.com/a", "2024-01-02")));
+    sync(&a).unwrap();
+    change(&b, |bookmarks| bookmarks.push(bookmark("https://example.com/b", "2024-01-03")));
+    // An untracked file in the way of the merge
+    std::fs::write(dir_b.join("notes.txt"), "from b").unwrap();
+    let head = git(&b, &["rev-parse", "HEAD"]).unwrap();
+    assert!(sync(&b).is_err());
+    assert_eq!(git(&b, &["rev-parse", "HEAD"]).unwrap(), head);
+    assert_eq!(hrefs(&b), ["https://example.com/1", "https://example.com/b"]);
+    // Once out of the way, the merge goes through
+    std::fs::remove_file(dir_b.join("notes.txt")).unwrap();
+    sync(&b).unwrap();
+    assert_eq!(hrefs(&b), ["https://example.com/1", "https://example.com/a", "https://example.com/b"]);
+    assert_eq!(git(&b, &["rev-list", "--count", "--merges", "HEAD"]).unwrap(), "1");
+  }
+}
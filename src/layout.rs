@@ -0,0 +1,476 @@
+// The layout of the data folder. Each bookmark has a directory named after the
+// hash of its url, under a directory named after the first two characters of
+// the hash so that no directory gets too large:
+//
+//   manifest.json                a marker of the layout of the folder and its version
+//   ab/<hash>/page.html          the stored page
+//   ab/<hash>/text.md            its text
+//   ab/<hash>/embeddings.bin     the embeddings of the search, as little endian f32
+//   ab/<hash>/snapshots/         the dated copies of the page, see snapshots.rs
+//   ab/<hash>/meta.json          the url and when each of the above was written
+//
+// The data folders of the previous versions hold flat files instead:
+// <hash>.html, <hash>.html.embeddings (JSON) and <hash>/ for the dated copies.
+// import_flat converts them.
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::snapshots::text_lines;
+use crate::storage;
+
+pub const PAGE: &str = "page.html";
+pub const TEXT: &str = "text.md";
+pub const EMBEDDINGS: &str = "embeddings.bin";
+pub const SNAPSHOTS: &str = "snapshots";
+const META: &str = "meta.json";
+const MANIFEST: &str = "manifest.json";
+
+const LAYOUT: &str = "sharded";
+const VERSION: u32 = 1;
+
+// Only marks the layout of the folder, the entries are not listed: each has its
+// meta.json
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+  layout: String,
+  version: u32,
+}
+
+// What the directory of a bookmark holds
+#[derive(Serialize, Deserialize, Default)]
+struct Meta {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  href: Option<String>,
+  // When each file was last written
+  files: BTreeMap<String, NaiveDateTime>,
+}
+
+// The directory of a bookmark
+pub struct Entry {
+  pub hash: String,
+  pub path: PathBuf,
+  pub size: u64,
+}
+
+// A file of a data folder in the flat layout
+pub struct FlatFile {
+  pub path: PathBuf,
+  // The hash of the url the file belongs to
+  pub hash: String,
+}
+
+// The hashes are SHA1 digests in base32
+fn is_hash(name: &str) -> bool {
+  name.len() == 32 && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+pub fn entry_dir(data_folder: &Path, hash: &str) -> PathBuf {
+  data_folder.join(&hash[..2]).join(hash)
+}
+
+fn size(path: &Path) -> u64 {
+  match std::fs::metadata(path) {
+    Ok(metadata) if metadata.is_dir() => std::fs::read_dir(path)
+      .map(|entries| entries.flatten().map(|entry| size(&entry.path())).sum())
+      .unwrap_or(0),
+    Ok(metadata) => metadata.len(),
+    Err(_) => 0,
+  }
+}
+
+// Prepares the data folder: a new one gets the manifest, an older one in the
+// flat layout has to be migrated.
+pub fn init(data_folder: &Path) -> Result<()> {
+  let path = data_folder.join(MANIFEST);
+  if path.exists() {
+    let manifest: Manifest = serde_json::from_str(&std::fs::read_to_string(&path)?)
+      .with_context(|| format!("could not read {}", path.display()))?;
+    if manifest.layout != LAYOUT || manifest.version > VERSION {
+      anyhow::bail!(
+        "{} uses the {} layout version {}, upgrade bookmark to use it",
+        data_folder.display(),
+        manifest.layout,
+        manifest.version
+      );
+    }
+    return Ok(());
+  }
+  if !flat_files(data_folder)?.is_empty() {
+    eprintln!(
+      "note: {} uses the previous layout, run `bookmark migrate --shard-data` to see the pages stored there",
+      data_folder.display()
+    );
+    return Ok(());
+  }
+  let manifest = Manifest { layout: LAYOUT.to_string(), version: VERSION };
+  std::fs::write(&path, serde_json::to_string_pretty(&manifest)?)?;
+  Ok(())
+}
+
+// A missing file has no files recorded yet. A corrupted one is an error rather
+// than empty, which would forget the dates of the files when writing it back.
+fn read_meta(dir: &Path) -> Result<Meta> {
+  let path = dir.join(META);
+  match std::fs::read_to_string(&path) {
+    Ok(content) => serde_json::from_str(&content).with_context(|| format!("could not read {}", path.display())),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Meta::default()),
+    Err(e) => Err(e).with_context(|| format!("could not read {}", path.display())),
+  }
+}
+
+fn write_meta(dir: &Path, meta: &Meta) -> Result<()> {
+  storage::write_atomic(&dir.join(META), 0, |writer| {
+    serde_json::to_writer_pretty(writer, meta)?;
+    Ok(())
+  })
+}
+
+// Records the files in the metadata of the directory. A name may be in a
+// subdirectory (snapshots/<timestamp>.html), which is what gets recorded.
+pub fn record(dir: &Path, href: &str, names: &[&str]) -> Result<()> {
+  let mut meta = read_meta(dir)?;
+  meta.href = Some(href.to_string());
+  let now = chrono::offset::Utc::now().naive_utc();
+  for name in names {
+    let top = name.split('/').next().unwrap_or(name);
+    meta.files.insert(top.to_string(), now);
+  }
+  write_meta(dir, &meta)
+}
+
+fn write(dir: &Path, name: &str, content: &[u8]) -> Result<()> {
+  let path = dir.join(name);
+  if let Some(parent) = path.parent() {
+    std::fs::create_dir_all(parent)?;
+  }
+  std::fs::write(&path, content).with_context(|| format!("error writing to {}", path.display()))
+}
+
+// Writes files of the directory of the url and records them at once
+pub fn write_files(dir: &Path, href: &str, files: &[(&str, &[u8])]) -> Result<()> {
+  for (name, content) in files {
+    write(dir, name, content)?;
+  }
+  let names: Vec<&str> = files.iter().map(|(name, _)| *name).collect();
+  record(dir, href, &names)
+}
+
+// Removes a file, or a directory, of the directory of a bookmark
+pub fn remove_file(dir: &Path, name: &str) -> Result<()> {
+  let path = dir.join(name);
+  if path.is_dir() {
+    std::fs::remove_dir_all(&path)?;
+  } else {
+    std::fs::remove_file(&path)?;
+  }
+  let mut meta = read_meta(dir)?;
+  if meta.files.remove(name).is_some() {
+    write_meta(dir, &meta)?;
+  }
+  Ok(())
+}
+
+// Removes the directory of a bookmark, and its shard once empty
+pub fn remove_entry(dir: &Path) -> Result<()> {
+  std::fs::remove_dir_all(dir)?;
+  if let Some(shard) = dir.parent() {
+    // Fails when other bookmarks are in the shard
+    let _ = std::fs::remove_dir(shard);
+  }
+  Ok(())
+}
+
+// Moves the files of a directory to another one, for another url. The page,
+// its text and its embeddings go together: the ones of the destination are
+// kept when it has any, the source ones dropped. The dated copies missing from
+// the destination are added to it.
+pub fn move_entry(from: &Path, to: &Path, href: &str) -> Result<()> {
+  if from == to || !from.exists() {
+    return Ok(());
+  }
+  let mut meta = if !to.exists() {
+    if let Some(shard) = to.parent() {
+      std::fs::create_dir_all(shard)?;
+    }
+    std::fs::rename(from, to)?;
+    if let Some(shard) = from.parent() {
+      let _ = std::fs::remove_dir(shard);
+    }
+    read_meta(to)?
+  } else {
+    let from_meta = read_meta(from)?;
+    let mut meta = read_meta(to)?;
+    let mut moved = vec![];
+    let group = [PAGE, TEXT, EMBEDDINGS];
+    if !group.iter().any(|name| to.join(name).exists()) {
+      moved.extend(group.iter().map(|name| name.to_string()).filter(|name| from.join(name).exists()));
+    }
+    for entry in std::fs::read_dir(from)? {
+      let name = entry?.file_name().to_string_lossy().to_string();
+      if name != META && !group.contains(&name.as_str()) && name != SNAPSHOTS && !to.join(&name).exists() {
+        moved.push(name);
+      }
+    }
+    for name in &moved {
+      std::fs::rename(from.join(name), to.join(name))?;
+      if let Some(date) = from_meta.files.get(name) {
+        meta.files.insert(name.clone(), *date);
+      }
+    }
+    if from.join(SNAPSHOTS).is_dir() {
+      std::fs::create_dir_all(to.join(SNAPSHOTS))?;
+      for entry in std::fs::read_dir(from.join(SNAPSHOTS))? {
+        let entry = entry?;
+        let destination = to.join(SNAPSHOTS).join(entry.file_name());
+        if !destination.exists() {
+          std::fs::rename(entry.path(), destination)?;
+        }
+      }
+      if let Some(date) = from_meta.files.get(SNAPSHOTS) {
+        let latest = meta.files.get(SNAPSHOTS).map_or(*date, |known| (*known).max(*date));
+        meta.files.insert(SNAPSHOTS.to_string(), latest);
+      }
+    }
+    remove_entry(from)?;
+    meta
+  };
+  meta.href = Some(href.to_string());
+  write_meta(to, &meta)
+}
+
+// The text of a page as Markdown paragraphs
+pub fn page_text(html: &str) -> String {
+  text_lines(html).lines().map(|line| format!("{}\n\n", line)).collect()
+}
+
+pub fn encode_embeddings(embeddings: &[f32]) -> Vec<u8> {
+  embeddings.iter().flat_map(|e| e.to_le_bytes()).collect()
+}
+
+pub fn decode_embeddings(bytes: &[u8]) -> Result<Vec<f32>> {
+  if bytes.is_empty() || !bytes.len().is_multiple_of(4) {
+    anyhow::bail!("invalid embeddings of {} bytes", bytes.len());
+  }
+  Ok(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+}
+
+// Lists the directories of the bookmarks
+pub fn entries(data_folder: &Path) -> Result<Vec<Entry>> {
+  let mut entries = vec![];
+  for shard in std::fs::read_dir(data_folder)? {
+    let shard = shard?.path();
+    let is_shard = shard.file_name().is_some_and(|name| name.len() == 2);
+    if !is_shard || !shard.is_dir() {
+      continue;
+    }
+    for entry in std::fs::read_dir(&shard)? {
+      let path = entry?.path();
+      let Some(hash) = path.file_name().map(|name| name.to_string_lossy().to_string()) else {
+        continue;
+      };
+      if is_hash(&hash) && path.is_dir() {
+        entries.push(Entry { hash, size: size(&path), path });
+      }
+    }
+  }
+  entries.sort_by(|a, b| a.hash.cmp(&b.hash));
+  Ok(entries)
+}
+
+// Lists the files of a data folder in the flat layout
+pub fn flat_files(folder: &Path) -> Result<Vec<FlatFile>> {
+  let mut files = vec![];
+  for entry in std::fs::read_dir(folder)? {
+    let path = entry?.path();
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+      continue;
+    };
+    let hash = if path.is_dir() {
+      name
+    } else if let Some(hash) = name.strip_suffix(".html.embeddings").or(name.strip_suffix(".html")) {
+      hash
+    } else {
+      continue;
+    };
+    if is_hash(hash) {
+      files.push(FlatFile { hash: hash.to_string(), path });
+    }
+  }
+  files.sort_by(|a, b| a.path.cmp(&b.path));
+  Ok(files)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+  std::fs::create_dir_all(to)?;
+  for entry in std::fs::read_dir(from)? {
+    let entry = entry?;
+    std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+  }
+  Ok(())
+}
+
+// Converts the flat files of a folder to directories of the data folder. The
+// files of the hashes kept are moved or, with copy, copied. The urls of the
+// hashes, when known, are recorded. Returns the number of files converted.
+pub fn import_flat(
+  from: &Path,
+  data_folder: &Path,
+  hrefs: &HashMap<String, String>,
+  keep: impl Fn(&str) -> bool,
+  copy: bool,
+) -> Result<usize> {
+  let mut count = 0;
+  for file in flat_files(from)? {
+    if !keep(&file.hash) {
+      continue;
+    }
+    let dir = entry_dir(data_folder, &file.hash);
+    let href = hrefs.get(&file.hash).map(String::as_str).unwrap_or_default();
+    let is_embeddings = file.path.to_string_lossy().ends_with(".embeddings");
+    let name = if file.path.is_dir() {
+      SNAPSHOTS
+    } else if is_embeddings {
+      EMBEDDINGS
+    } else {
+      PAGE
+    };
+    if dir.join(name).exists() {
+      continue;
+    }
+    std::fs::create_dir_all(&dir)?;
+    if file.path.is_dir() {
+      if copy {
+        copy_dir(&file.path, &dir.join(SNAPSHOTS))?;
+      } else {
+        std::fs::rename(&file.path, dir.join(SNAPSHOTS))?;
+      }
+      record(&dir, href, &[SNAPSHOTS])?;
+    } else if is_embeddings {
+      let embeddings: Vec<f32> = serde_json::from_slice(&std::fs::read(&file.path)?)
+        .with_context(|| format!("could not read {}", file.path.display()))?;
+      write_files(&dir, href, &[(EMBEDDINGS, &encode_embeddings(&embeddings))])?;
+      if !copy {
+        std::fs::remove_file(&file.path)?;
+      }
+    } else {
+      let page = std::fs::read(&file.path)?;
+      write(&dir, TEXT, page_text(&String::from_utf8_lossy(&page)).as_bytes())?;
+      if copy {
+        std::fs::copy(&file.path, dir.join(PAGE))?;
+      } else {
+        std::fs::rename(&file.path, dir.join(PAGE))?;
+      }
+      record(&dir, href, &[TEXT, PAGE])?;
+    }
+    count += 1;
+  }
+  Ok(count)
+}
+
+// Converts the data folder from the flat layout
+pub fn migrate(data_folder: &Path, hrefs: &HashMap<String, String>) -> Result<usize> {
+  let count = import_flat(data_folder, data_folder, hrefs, |_| true, false)?;
+  let manifest = Manifest { layout: LAYOUT.to_string(), version: VERSION };
+  std::fs::write(data_folder.join(MANIFEST), serde_json::to_string_pretty(&manifest)?)?;
+  Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const HASH: &str = "abcdefghijklmnopqrstuvwxyz234567";
+  const OTHER_HASH: &str = "bcdefghijklmnopqrstuvwxyz2345678";
+
+  fn read(dir: &Path, name: &str) -> String {
+    std::fs::read_to_string(dir.join(name)).unwrap()
+  }
+
+  #[test]
+  fn moves_the_page_with_its_text_and_embeddings() {
+    let folder = tempfile::tempdir().unwrap();
+    let from = entry_dir(folder.path(), HASH);
+    let to = entry_dir(folder.path(), OTHER_HASH);
+    write_files(&from, "https://example.com/a", &[(PAGE, b"from page"), (TEXT, b"from text")]).unwrap();
+    write_files(&from, "https://example.com/a", &[(EMBEDDINGS, b"from embeddings")]).unwrap();
+    write_files(&from, "https://example.com/a", &[("snapshots/1.html", b"from snapshot")]).unwrap();
+    write_files(&to, "https://example.com/b", &[(PAGE, b"to page")]).unwrap();
+    write_files(&to, "https://example.com/b", &[("snapshots/2.html", b"to snapshot")]).unwrap();
+    move_entry(&from, &to, "https://example.com/b").unwrap();
+    assert!(!from.exists());
+    // The destination page is kept, without the text and embeddings of another page
+    assert_eq!(read(&to, PAGE), "to page");
+    assert!(!to.join(TEXT).exists());
+    assert!(!to.join(EMBEDDINGS).exists());
+    assert_eq!(read(&to, "snapshots/1.html"), "from snapshot");
+    assert_eq!(read(&to, "snapshots/2.html"), "to snapshot");
+    let meta = read_meta(&to).unwrap();
+    assert_eq!(meta.href.as_deref(), Some("https://example.com/b"));
+    assert_eq!(meta.files.keys().collect::<Vec<_>>(), [PAGE, SNAPSHOTS]);
+  }
+
+  #[test]
+  fn moves_the_whole_group_to_a_destination_without_page() {
+    let folder = tempfile::tempdir().unwrap();
+    let from = entry_dir(folder.path(), HASH);
+    let to = entry_dir(folder.path(), OTHER_HASH);
+    write_files(&from, "https://example.com/a", &[(PAGE, b"from page"), (TEXT, b"from text")]).unwrap();
+    write_files(&to, "https://example.com/b", &[("snapshots/2.html", b"to snapshot")]).unwrap();
+    move_entry(&from, &to, "https://example.com/b").unwrap();
+    assert_eq!(read(&to, PAGE), "from page");
+    assert_eq!(read(&to, TEXT), "from text");
+    assert_eq!(read_meta(&to).unwrap().files.keys().collect::<Vec<_>>(), [PAGE, SNAPSHOTS, TEXT]);
+  }
+
+  #[test]
+  fn refuses_a_corrupted_meta() {
+    let folder = tempfile::tempdir().unwrap();
+    let dir = entry_dir(folder.path(), HASH);
+    write_files(&dir, "https://example.com/a", &[(PAGE, b"page")]).unwrap();
+    std::fs::write(dir.join(META), "{").unwrap();
+    assert!(write_files(&dir, "https://example.com/a", &[(TEXT, b"text")]).is_err());
+  }
+
+  #[test]
+  fn encodes_the_embeddings() {
+    let embeddings = [0.5, -1.25, f32::MAX];
+    let bytes = encode_embeddings(&embeddings);
+    assert_eq!(bytes.len(), 12);
+    assert_eq!(&bytes[..4], &0.5f32.to_le_bytes());
+    assert_eq!(decode_embeddings(&bytes).unwrap(), embeddings);
+    assert!(decode_embeddings(&[]).is_err());
+    assert!(decode_embeddings(&bytes[..5]).is_err());
+  }
+
+  #[test]
+  fn imports_the_flat_files() {
+    let from = tempfile::tempdir().unwrap();
+    let folder = tempfile::tempdir().unwrap();
+    std::fs::write(from.path().join(format!("{}.html", HASH)), "<html><body><p>Hello</p></body></html>").unwrap();
+    std::fs::write(from.path().join(format!("{}.html.embeddings", HASH)), "[0.5, 1.0]").unwrap();
+    std::fs::create_dir(from.path().join(HASH)).unwrap();
+    std::fs::write(from.path().join(HASH).join("20240101000000.html"), "old").unwrap();
+    std::fs::write(from.path().join(format!("{}.html", OTHER_HASH)), "<html></html>").unwrap();
+    std::fs::write(from.path().join("notes.txt"), "not a page").unwrap();
+    let hrefs = HashMap::from([(HASH.to_string(), "https://example.com/a".to_string())]);
+    let count = import_flat(from.path(), folder.path(), &hrefs, |hash| hash == HASH, true).unwrap();
+    assert_eq!(count, 3);
+    let dir = entry_dir(folder.path(), HASH);
+    assert_eq!(read(&dir, PAGE), "<html><body><p>Hello</p></body></html>");
+    assert_eq!(read(&dir, TEXT), "Hello\n\n");
+    assert_eq!(decode_embeddings(&std::fs::read(dir.join(EMBEDDINGS)).unwrap()).unwrap(), [0.5, 1.0]);
+    assert_eq!(read(&dir, "snapshots/20240101000000.html"), "old");
+    let meta = read_meta(&dir).unwrap();
+    assert_eq!(meta.href.as_deref(), Some("https://example.com/a"));
+    assert_eq!(meta.files.keys().collect::<Vec<_>>(), [EMBEDDINGS, PAGE, SNAPSHOTS, TEXT]);
+    // Copied, and the hashes not kept are left out
+    assert!(from.path().join(format!("{}.html", HASH)).exists());
+    assert!(!entry_dir(folder.path(), OTHER_HASH).exists());
+    // Importing again finds the files already there
+    assert_eq!(import_flat(from.path(), folder.path(), &hrefs, |_| true, false).unwrap(), 1);
+    assert!(!from.path().join(format!("{}.html", OTHER_HASH)).exists());
+  }
+}
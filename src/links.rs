@@ -0,0 +1,140 @@
+// Checking that the bookmarked links are still alive.
+// The redirects are followed by hand to tell the links which moved for good
+// (301 and 308 all the way) from the ones temporarily redirected.
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::{get_hash, http_client, Bookmark, Config, UrlStore};
+
+const MAX_REDIRECTS: usize = 10;
+
+fn is_zero(n: &u32) -> bool {
+  *n == 0
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LinkStatus {
+  // When the link was checked
+  pub date: NaiveDateTime,
+  // The status code of the response, once the redirects followed
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub status: Option<u16>,
+  // Where the link permanently redirects to
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub redirect: Option<String>,
+  // Why the request failed
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+  // The number of consecutive checks which found the link dead
+  #[serde(default, skip_serializing_if = "is_zero")]
+  pub failures: u32,
+}
+
+impl LinkStatus {
+  pub fn is_dead(&self) -> bool {
+    self.error.is_some() || self.status.is_some_and(|status| status >= 400)
+  }
+}
+
+// Sends a HEAD request, falling back to GET for the servers which do not
+// support it
+fn request(client: &Client, url: &Url) -> reqwest::Result<Response> {
+  match client.head(url.clone()).send() {
+    Ok(response)
+      if !matches!(
+        response.status(),
+        StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED | StatusCode::FORBIDDEN
+      ) =>
+    {
+      Ok(response)
+    }
+    _ => client.get(url.clone()).send(),
+  }
+}
+
+// Returns the final status code and, if the link permanently redirects, its
+// target
+fn follow(client: &Client, href: &str) -> Result<(u16, Option<String>)> {
+  let mut url = Url::parse(href)?;
+  let mut permanent = true;
+  let mut redirected = false;
+  for _ in 0..MAX_REDIRECTS {
+    let response = request(client, &url)?;
+    let status = response.status();
+    if !status.is_redirection() {
+      let redirect = (redirected && permanent).then(|| url.to_string());
+      return Ok((status.as_u16(), redirect));
+    }
+    permanent &= matches!(status, StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT);
+    redirected = true;
+    let location = response
+      .headers()
+      .get(LOCATION)
+      .and_then(|location| location.to_str().ok())
+      .context("redirect without location")?;
+    url = url.join(location)?;
+  }
+  anyhow::bail!("too many redirects")
+}
+
+// Checks the link of a bookmark
+pub fn check(client: &Client, bookmark: &Bookmark) -> LinkStatus {
+  let date = chrono::offset::Utc::now().naive_utc();
+  let mut status = match follow(client, &bookmark.href) {
+    Ok((status, redirect)) => LinkStatus { date, status: Some(status), redirect, error: None, failures: 0 },
+    Err(e) => LinkStatus { date, status: None, redirect: None, error: Some(format!("{:#}", e)), failures: 0 },
+  };
+  if status.is_dead() {
+    status.failures = bookmark.meta.link.as_ref().map_or(0, |link| link.failures) + 1;
+  }
+  status
+}
+
+// Checks the links of all the bookmarks, recording the outcome in their
+// metadata, and reports the dead and moved ones. With update_redirects, the
+// links permanently redirected are replaced by their target.
+// Returns the replaced links along with their replacement.
+pub fn check_all(
+  config: &Config,
+  url_store: &UrlStore,
+  bookmarks: &mut [Bookmark],
+  update_redirects: bool,
+) -> Result<Vec<(String, String)>> {
+  let client = http_client(Policy::none())?;
+  let mut dead = 0;
+  let mut moved = 0;
+  let mut updated = vec![];
+  for bookmark in bookmarks.iter_mut() {
+    let mut link = check(&client, bookmark);
+    if link.is_dead() {
+      dead += 1;
+      let reason = match (&link.status, &link.error) {
+        (_, Some(error)) => error.clone(),
+        (Some(status), None) => status.to_string(),
+        (None, None) => String::new(),
+      };
+      println!("dead {} ({}, {} failed checks in a row)", bookmark.href, reason, link.failures);
+    } else if let Some(redirect) = &link.redirect {
+      moved += 1;
+      let target = redirect.clone();
+      if update_redirects && target != bookmark.href {
+        println!("updated {} -> {}", bookmark.href, target);
+        url_store.move_to(&bookmark.href, &target)?;
+        updated.push((bookmark.href.clone(), target.clone()));
+        bookmark.hash = get_hash(&target);
+        bookmark.href = target;
+        link.redirect = None;
+      } else {
+        println!("moved {} -> {}", bookmark.href, redirect);
+      }
+    }
+    bookmark.meta.link = Some(link);
+  }
+  println!("checked {} links: {} dead, {} moved", bookmarks.len(), dead, moved);
+  Ok(updated)
+}
@@ -5,17 +5,42 @@
 
 use anyhow::{Error as E, Context, Result};
 use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
-use clap::{Parser, Subcommand};
-use reqwest;
+use clap::{Args, Parser, Subcommand};
 use scraper::{Html, Selector};
 use serde::Deserializer;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::io::prelude::*;
 use std::path::PathBuf;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use users::{get_current_uid, get_user_by_uid};
 
+mod archive;
+mod canonical;
+mod export;
+mod gc;
+mod git;
+mod layout;
+mod links;
+mod merge;
+mod native;
+mod notes;
+mod schema;
+mod server;
+mod snapshots;
+mod sqlite;
+mod stats;
+mod storage;
+mod tui;
+mod web;
+
+use canonical::{canonical_link, canonicalize};
+use sqlite::SqliteStore;
+use storage::write_bookmarks;
+
 pub const DEFAULT_CONFIG: &str = include_str!("../config.yaml");
 pub const USER_AGENT_STRING: &str =
   "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
@@ -49,14 +74,315 @@ struct Opt {
 
 #[derive(Debug, Subcommand)]
 enum Commands {
-  /// Adds a bookmark
-  Add { url: String },
-  /// Search the needle among the articles
-  Search { needle: Vec<String> },
+  /// Adds a bookmark. With "-" as url, adds the urls read from the standard input, one per line
+  Add {
+    url: String,
+    #[command(flatten)]
+    options: AddOptions,
+  },
+  /// List the bookmarks (default command)
+  List {
+    #[command(flatten)]
+    filter: Filter,
+    #[command(flatten)]
+    options: ListOptions,
+  },
+  /// Open bookmarks in $BROWSER or the default browser
+  Open {
+    /// The hashes of the bookmarks or their positions, as printed by list
+    #[arg(required = true, value_name = "HASH|INDEX")]
+    bookmarks: Vec<String>,
+    /// Open the stored copy of the page instead
+    #[arg(long)]
+    offline: bool,
+  },
+  /// Search the needle among the articles, their notes and highlights
+  Search {
+    needle: Vec<String>,
+    /// Search for the words instead of using the semantic search
+    #[arg(long)]
+    keyword: bool,
+    #[command(flatten)]
+    filter: Filter,
+  },
+  /// Print the bookmarks as an HTML bookmark file (importable by browsers) or as Markdown
+  Export {
+    #[arg(long, value_enum, default_value = "html")]
+    format: export::Format,
+    #[command(flatten)]
+    filter: Filter,
+  },
+  /// Print statistics about the bookmarks and the data folder
+  Stats {
+    /// Print them as JSON
+    #[arg(long)]
+    json: bool,
+  },
+  /// Browse the bookmarks in a terminal interface, filtering them as you type
+  Tui {},
   /// Print the url associated with the provided hash if present in the bookmark file
   Hash { hash: String },
   /// Check the index
-  Check { },
+  Check {
+    /// Check that the links are alive instead and report the dead and moved ones
+    #[arg(long)]
+    links: bool,
+    /// Replace the links permanently redirected by their target
+    #[arg(long, requires = "links")]
+    update_redirects: bool,
+  },
+  /// Remove the bookmarks with the provided hashes
+  Remove {
+    #[arg(required = true)]
+    hash: Vec<String>,
+  },
+  /// Edit the notes and the highlights of a bookmark in $EDITOR
+  Note { hash: String },
+  /// Fetch the pages again and keep a dated copy of the ones which changed
+  Refresh {
+    /// Only this bookmark
+    hash: Option<String>,
+  },
+  /// Show the differences between two copies of a page kept by refresh
+  Diff {
+    hash: String,
+    /// The copy to compare from, as listed by --list. Default: the one before --to
+    #[arg(long, value_name = "TIMESTAMP")]
+    from: Option<String>,
+    /// The copy to compare to. Default: the most recent one
+    #[arg(long, value_name = "TIMESTAMP")]
+    to: Option<String>,
+    /// List the copies instead
+    #[arg(long)]
+    list: bool,
+  },
+  /// Mark bookmarks as read
+  Read {
+    #[arg(required = true)]
+    hash: Vec<String>,
+    /// Mark them as unread instead
+    #[arg(long)]
+    unread: bool,
+  },
+  /// Star bookmarks
+  Star {
+    #[arg(required = true)]
+    hash: Vec<String>,
+    /// Unstar them instead
+    #[arg(long)]
+    remove: bool,
+  },
+  /// Archive bookmarks, which hides them from the listing and the search
+  Archive {
+    #[arg(required = true)]
+    hash: Vec<String>,
+    /// Unarchive them instead
+    #[arg(long)]
+    remove: bool,
+  },
+  /// Pull the bookmarks from the git remote, merge them and push the result
+  Sync {},
+  /// Merge two versions of a bookmark file derived from a common one, writing the
+  /// result to OURS. Exits with 1 on conflicts. Can be used as a git merge driver:
+  /// `bookmark merge %O %A %B`
+  Merge {
+    /// The common ancestor
+    base: PathBuf,
+    /// Our version, replaced by the result
+    ours: PathBuf,
+    /// Their version
+    theirs: PathBuf,
+  },
+  /// Serve the bookmarks through a JSON API
+  Serve {
+    /// The address to listen on. Default: the configured one, otherwise 127.0.0.1:8080
+    #[arg(long, value_name = "ADDRESS")]
+    listen: Option<String>,
+  },
+  /// Run as the native messaging host of a browser extension: read the pages to add
+  /// on the standard input and write the results on the standard output
+  NativeHost {
+    /// The arguments given by the browser, ignored
+    #[arg(hide = true)]
+    caller: Vec<String>,
+  },
+  /// List the files of the data folder belonging to no bookmark and the empty,
+  /// truncated or unreadable ones
+  Gc {
+    /// Delete them, except the truncated pages
+    #[arg(long)]
+    delete: bool,
+  },
+  /// Merge the bookmarks sharing the same canonical url
+  Dedup {
+    /// Only print the bookmarks that would be merged
+    #[arg(long)]
+    dry_run: bool,
+  },
+  /// Upgrade the bookmark file to the current schema version or, with --to, copy
+  /// the bookmarks and their embeddings to another storage backend
+  Migrate {
+    /// Copy the stored pages and embeddings of the bookmark file from the data folder
+    /// shared by all the bookmark files to its own instead
+    #[arg(long, conflicts_with_all = ["to", "output"], verbatim_doc_comment)]
+    split_data: bool,
+    /// Move the stored pages and embeddings of the data folder from the flat layout of
+    /// the previous versions to a directory for each bookmark
+    #[arg(long, conflicts_with_all = ["to", "output", "split_data"], verbatim_doc_comment)]
+    shard_data: bool,
+    /// The backend to convert to
+    #[arg(long, value_enum)]
+    to: Option<storage::Backend>,
+    /// The file to create.
+    /// Default: the bookmark file with the extension of the backend (.db or .json)
+    #[arg(short, long, value_name = "FILE", verbatim_doc_comment)]
+    output: Option<PathBuf>,
+  },
+  /// Rewrite the urls to their canonical form and merge the resulting duplicates
+  Canonicalize {
+    /// Only print the urls that would be rewritten
+    #[arg(long)]
+    dry_run: bool,
+  },
+}
+
+// How a bookmark is added
+#[derive(Debug, Default, Args)]
+struct AddOptions {
+  /// The title, instead of the one of the page
+  #[arg(long)]
+  title: Option<String>,
+  /// Do not fetch the page
+  #[arg(long)]
+  no_fetch: bool,
+  /// Notes on the bookmark, in Markdown
+  #[arg(long)]
+  note: Option<String>,
+  /// When the bookmark was posted instead of now: YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS
+  #[arg(long, value_parser = parse_date)]
+  posted: Option<NaiveDateTime>,
+  /// Tag the bookmark. Can be repeated
+  #[arg(long = "tag", value_name = "TAG")]
+  tags: Vec<String>,
+  /// Do not add the bookmark if the page cannot be fetched. The error is printed on
+  /// the standard error as JSON ({"url": ..., "error": ...}) and the exit status is 1
+  #[arg(long, conflicts_with = "no_fetch", verbatim_doc_comment)]
+  strict: bool,
+  // The page as rendered by the browser, stored instead of fetching it. Given
+  // by the browser extensions, see native.rs and POST /bookmarks.
+  #[arg(skip)]
+  html: Option<String>,
+}
+
+// How the bookmarks are listed
+#[derive(Debug, Default, Args)]
+struct ListOptions {
+  /// Print each bookmark with this template instead, e.g. '{hash}\t{title}\t{href}'.
+  /// The fields are {index} (the position), {hash}, {title}, {href}, {tags}, {user}
+  /// and {posted}. \t, \n and \\ are replaced by a tab, a new line and a backslash
+  #[arg(long, value_name = "TEMPLATE", value_parser = parse_template, verbatim_doc_comment)]
+  format: Option<String>,
+  /// Separate the bookmarks by a null character instead of a new line, for
+  /// fzf --read0 or xargs -0
+  #[arg(long, verbatim_doc_comment)]
+  null: bool,
+  /// Sort the bookmarks instead of keeping the order of the file
+  #[arg(long, value_enum)]
+  sort: Option<SortKey>,
+  /// Reverse the order
+  #[arg(long)]
+  reverse: bool,
+  /// Print at most this number of bookmarks
+  #[arg(long, value_name = "N")]
+  limit: Option<usize>,
+  /// Skip this number of bookmarks
+  #[arg(long, value_name = "N", default_value_t = 0)]
+  offset: usize,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum SortKey {
+  Posted,
+  Title,
+  // Then by date
+  Domain,
+}
+
+// Restricts the bookmarks a command applies to
+#[derive(Debug, Default, Args)]
+struct Filter {
+  /// Only the bookmarks added by this user
+  #[arg(long, value_name = "USER")]
+  user: Option<String>,
+  /// Only the bookmarks added by the current user (see the `user` configuration option)
+  #[arg(long, conflicts_with = "user")]
+  mine: bool,
+  /// Only the bookmarks not read yet
+  #[arg(long)]
+  unread: bool,
+  /// Only the starred bookmarks
+  #[arg(long)]
+  starred: bool,
+  /// Only the archived bookmarks, which are hidden otherwise
+  #[arg(long, conflicts_with = "all")]
+  archived: bool,
+  /// Include the archived bookmarks
+  #[arg(long)]
+  all: bool,
+  /// Only the bookmarks posted since this date: YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS
+  #[arg(long, value_name = "DATE", value_parser = parse_date)]
+  since: Option<NaiveDateTime>,
+  /// Only the bookmarks posted until this date, included
+  #[arg(long, value_name = "DATE", value_parser = parse_until)]
+  until: Option<NaiveDateTime>,
+  /// Only the bookmarks of this domain or of its subdomains
+  #[arg(long)]
+  domain: Option<String>,
+  /// Only the bookmarks found on a page whose url contains this text
+  #[arg(long)]
+  referer: Option<String>,
+}
+
+impl Filter {
+  fn matches(&self, config: &Config, bookmark: &Bookmark) -> bool {
+    let user = if self.mine { Some(current_user(config)) } else { self.user.clone() };
+    user.is_none_or(|user| bookmark.meta.user.as_ref() == Some(&user))
+      && (!self.unread || bookmark.meta.read.is_none())
+      && (!self.starred || bookmark.meta.starred)
+      && (self.all || self.archived == bookmark.meta.archived)
+      && self.since.is_none_or(|since| bookmark.meta.posted.is_some_and(|posted| posted >= since))
+      && self.until.is_none_or(|until| bookmark.meta.posted.is_some_and(|posted| posted <= until))
+      && self.domain.as_ref().is_none_or(|domain| {
+        // domain_of drops the www. of the bookmarks, and so of the domain
+        let domain = domain.strip_prefix("www.").unwrap_or(domain);
+        let host = domain_of(&bookmark.href);
+        host == *domain || host.ends_with(&format!(".{}", domain))
+      })
+      && self.referer.as_ref().is_none_or(|referer| {
+        bookmark.meta.referer.iter().chain(&bookmark.meta.referers).any(|r| r.contains(referer.as_str()))
+      })
+  }
+
+  fn apply<'a>(&self, config: &Config, bookmarks: &'a [Bookmark]) -> Vec<&'a Bookmark> {
+    bookmarks.iter().filter(|b| self.matches(config, b)).collect()
+  }
+}
+
+// The outcome of the last attempt to fetch the article of a bookmark
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct FetchStatus {
+  date: NaiveDateTime,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+impl FetchStatus {
+  fn new(result: &Result<impl Sized>) -> Self {
+    FetchStatus {
+      date: chrono::offset::Utc::now().naive_utc(),
+      error: result.as_ref().err().map(|e| e.to_string()),
+    }
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -65,8 +391,35 @@ struct Metadata {
   posted: Option<NaiveDateTime>,
   #[serde(skip_serializing_if = "Option::is_none")]
   user: Option<String>,
+  // The page the url was found on
   #[serde(skip_serializing_if = "Option::is_none")]
   referer: Option<String>,
+  // The pages the duplicates merged into this bookmark were found on
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  referers: Vec<String>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  tags: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  fetch: Option<FetchStatus>,
+  // When the bookmark was marked as read, None if it is unread
+  #[serde(skip_serializing_if = "Option::is_none")]
+  read: Option<NaiveDateTime>,
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  starred: bool,
+  // Archived bookmarks are hidden from the listing and the search
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  archived: bool,
+  // Free text, in Markdown
+  #[serde(skip_serializing_if = "Option::is_none")]
+  notes: Option<String>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  highlights: Vec<notes::Highlight>,
+  // The outcome of the last check of the link, see `check --links`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  link: Option<links::LinkStatus>,
+  // Fields unknown to this version, kept as is
+  #[serde(flatten)]
+  extra: serde_json::Map<String, serde_json::Value>,
 }
 
 // This is the representation of Bookmark when serialize
@@ -75,6 +428,8 @@ struct BookmarkRepr {
   href: String,
   meta: Metadata,
   title: String,
+  #[serde(flatten)]
+  extra: serde_json::Map<String, serde_json::Value>,
 }
 
 // This is a bookmark with computed fields (hash)
@@ -86,6 +441,9 @@ struct Bookmark {
   hash: String,
   meta: Metadata,
   title: String,
+  // Fields unknown to this version, kept as is
+  #[serde(flatten)]
+  extra: serde_json::Map<String, serde_json::Value>,
 }
 
 // https://github.com/serde-rs/serde/issues/1689#issuecomment-653831474
@@ -100,6 +458,7 @@ impl<'de> Deserialize<'de> for Bookmark {
       href: bookmark.href,
       meta: bookmark.meta,
       title: bookmark.title,
+      extra: bookmark.extra,
     })
   }
 }
@@ -110,13 +469,63 @@ struct ChromiumConfig {
   path: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct CanonicalizeConfig {
+  enabled: bool,
+  // Rewrite http urls to https
+  https: Option<bool>,
+  // Remove the leading www. of the host
+  strip_www: Option<bool>,
+  // Remove the #fragment
+  strip_fragment: Option<bool>,
+  // Remove the trailing slashes of the path
+  strip_trailing_slash: Option<bool>,
+  // Query parameters to remove. A trailing '*' matches any suffix.
+  // default: canonical::DEFAULT_TRACKING_PARAMS
+  tracking_params: Option<Vec<String>>,
+  // Use the <link rel="canonical"> of the fetched page when present
+  use_canonical_link: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GitConfig {
+  enabled: bool,
+  // The remote to sync with
+  // default: origin
+  remote: Option<String>,
+  // The branch to sync with
+  // default: the current branch
+  branch: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchiveConfig {
+  // Fetch the pages which cannot be fetched anymore from the archive
+  enabled: bool,
+  // The url of the Internet Archive, or of a service with the same API
+  // default: archive::INTERNET_ARCHIVE_URL
+  base_url: Option<String>,
+  // Submit every new bookmark to the archive
+  // default: false
+  submit: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ServerConfig {
+  // The address `bookmark serve` listens on
+  // default: server::DEFAULT_LISTEN
+  listen: Option<String>,
+  // When set, the requests must come with this bearer token
+  token: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
   // Where to load the bookmark file.
   // Default is ~/bookmarks.json
   bookmarks: PathBuf,
   // Keep a local copy of the article
-  // Kept in XDG_DATA_HOME
+  // Kept in the data folder, see data_dir
   // default: false
   store_articles: Option<bool>,
   // Enable search feature.
@@ -126,34 +535,132 @@ struct Config {
   // The config used to launch chromium to retrieve the page content including
   // with javascript enabled.
   chromium: Option<ChromiumConfig>,
+  // How urls are normalised before being stored and hashed.
+  canonicalize: Option<CanonicalizeConfig>,
+  // Number of rotating backups of the bookmark file (<file>.bak.1 being the
+  // most recent). 0 disables the backups.
+  // default: 3
+  backups: Option<usize>,
+  // The format of the bookmark file: json, pretty-json, json-lines, yaml, toml
+  // or sqlite.
+  // default: guessed from the extension of the bookmark file, json otherwise
+  format: Option<storage::Format>,
+  // Commit the bookmark file in the git repository it belongs to everytime it
+  // is modified.
+  git: Option<GitConfig>,
+  // The name recorded as the user adding bookmarks, for people sharing a
+  // bookmark file under a handle different from their login.
+  // default: the login name
+  user: Option<String>,
+  // An archive service, such as the Wayback Machine
+  archive: Option<ArchiveConfig>,
+  // The configuration of `bookmark serve`
+  server: Option<ServerConfig>,
+  // Where the stored pages and the embeddings of the bookmark file are kept.
+  // A relative path is relative to the directory of the bookmark file.
+  // default: a folder named after the bookmark file in XDG_DATA_HOME/bookmark/
+  data_dir: Option<PathBuf>,
 }
 
-// Writes bookmarks to a file.
-fn write_bookmarks(bookmarks: &Vec<Bookmark>, output_file: &PathBuf) -> Result<()> {
-  let file = std::fs::File::create(output_file)?;
-  let mut writer = std::io::BufWriter::new(file);
-  serde_json::to_writer(&mut writer, &bookmarks)?;
-  Ok(())
+// The result of a dedup run
+struct Dedup {
+  bookmarks: Vec<Bookmark>,
+  // The hrefs of the bookmarks which absorbed duplicates along with the
+  // hrefs of the absorbed duplicates
+  merged: Vec<(String, Vec<String>)>,
+  // Whether the bookmarks differ from the input, either because duplicates
+  // were merged or because the order changed
+  changed: bool,
+}
+
+// Merges the fields of a duplicate into a bookmark. The earliest posted date
+// wins along with the user who posted it, empty fields are filled from the
+// duplicate, referers, tags and highlights are merged and the flags set on
+// either are kept.
+fn merge_bookmark(bookmark: &mut Bookmark, duplicate: &Bookmark) {
+  if let Some(posted) = duplicate.meta.posted {
+    if bookmark.meta.posted.is_none_or(|p| posted < p) {
+      bookmark.meta.posted = Some(posted);
+      if duplicate.meta.user.is_some() {
+        bookmark.meta.user = duplicate.meta.user.clone();
+      }
+    }
+  }
+  if bookmark.title.is_empty() {
+    bookmark.title = duplicate.title.clone();
+  }
+  if bookmark.meta.user.is_none() {
+    bookmark.meta.user = duplicate.meta.user.clone();
+  }
+  for referer in duplicate.meta.referer.iter().chain(&duplicate.meta.referers) {
+    if bookmark.meta.referer.is_none() {
+      bookmark.meta.referer = Some(referer.clone());
+    } else if bookmark.meta.referer.as_ref() != Some(referer) && !bookmark.meta.referers.contains(referer) {
+      bookmark.meta.referers.push(referer.clone());
+    }
+  }
+  for tag in &duplicate.meta.tags {
+    if !bookmark.meta.tags.contains(tag) {
+      bookmark.meta.tags.push(tag.clone());
+    }
+  }
+  if bookmark.meta.read.is_none() {
+    bookmark.meta.read = duplicate.meta.read;
+  }
+  bookmark.meta.starred |= duplicate.meta.starred;
+  bookmark.meta.archived |= duplicate.meta.archived;
+  if bookmark.meta.notes.is_none() {
+    bookmark.meta.notes = duplicate.meta.notes.clone();
+  }
+  for highlight in &duplicate.meta.highlights {
+    if !bookmark.meta.highlights.iter().any(|h| h.text == highlight.text) {
+      bookmark.meta.highlights.push(highlight.clone());
+    }
+  }
 }
 
 // Takes a bookmarks list and returns the list without duplicate href.
-fn dedup(bookmarks: &[Bookmark], output_file: &PathBuf) -> Result<Vec<Bookmark>> {
+// Two hrefs are duplicates if their canonical form is the same. The first
+// occurrence is kept and the following ones are merged into it.
+fn dedup(config: &Config, bookmarks: &[Bookmark]) -> Dedup {
   let mut new_bookmarks: Vec<Bookmark> = Vec::with_capacity(bookmarks.len());
+  let mut merged: Vec<Vec<String>> = Vec::with_capacity(bookmarks.len());
+  let mut indexes: HashMap<String, usize> = HashMap::with_capacity(bookmarks.len());
   for bookmark in bookmarks {
-    if let None = new_bookmarks.iter().find(|b| b.href == bookmark.href) {
-      new_bookmarks.push(bookmark.clone());
+    match indexes.entry(canonicalize(config, &bookmark.href)) {
+      Entry::Occupied(entry) => {
+        merge_bookmark(&mut new_bookmarks[*entry.get()], bookmark);
+        merged[*entry.get()].push(bookmark.href.clone());
+      }
+      Entry::Vacant(entry) => {
+        entry.insert(new_bookmarks.len());
+        new_bookmarks.push(bookmark.clone());
+        merged.push(vec![]);
+      }
     }
   }
+  let merged: Vec<(String, Vec<String>)> = new_bookmarks
+    .iter()
+    .zip(merged)
+    .filter(|(_, duplicates)| !duplicates.is_empty())
+    .map(|(bookmark, duplicates)| (bookmark.href.clone(), duplicates))
+    .collect();
   // Let sort the bookmarks by date while we're at it
-  new_bookmarks.sort_by(|a, b| {
-    if a.meta.posted.is_none() || b.meta.posted.is_none() {
-      a.href.cmp(&b.href)
-    } else {
-      a.meta.posted.unwrap().cmp(&b.meta.posted.unwrap())
-    }
-  });
-  write_bookmarks(&new_bookmarks, output_file)?;
-  Ok(new_bookmarks)
+  new_bookmarks.sort_by_key(|b| b.meta.posted);
+  let changed = !merged.is_empty()
+    || new_bookmarks.iter().zip(bookmarks).any(|(a, b)| a.hash != b.hash);
+  Dedup { bookmarks: new_bookmarks, merged, changed }
+}
+
+// Creates the client used for the HTTP requests. It has a standard user agent
+// because some site do not accept "reqwest".
+fn http_client(redirect: reqwest::redirect::Policy) -> Result<reqwest::blocking::Client> {
+  Ok(
+    reqwest::blocking::Client::builder()
+      .user_agent(USER_AGENT_STRING)
+      .redirect(redirect)
+      .build()?,
+  )
 }
 
 // Fetches a URL with a fake user agent.
@@ -162,12 +669,7 @@ fn fetch_http(config: &Config, url: &str) -> Result<String> {
   if chromium_available(config) {
     fetch_by_chromium(url)
   } else {
-    let client = reqwest::blocking::Client::new();
-    // Creating a client with a standard USER_AGENT because some site do not accetp "reqwests".
-    let response = client
-      .get(url)
-      .header(reqwest::header::USER_AGENT, USER_AGENT_STRING)
-      .send()?;
+    let response = http_client(reqwest::redirect::Policy::default())?.get(url).send()?;
     // Check for status
     let response = match response.error_for_status() {
       Ok(response) => response,
@@ -177,18 +679,189 @@ fn fetch_http(config: &Config, url: &str) -> Result<String> {
   }
 }
 
-fn get_text(config: &Config, url: &str) -> Result<String> {
-  let body = fetch_http(config, url)?;
-  let document = Html::parse_document(&body);
-  let selector = Selector::parse(r#"body :not(style)"#).unwrap();
-  let text_content = document.select(&selector).next().unwrap();
-  Ok(text_content.text().collect::<Vec<_>>().join(""))
+// The fields a list template may use, e.g. "{index} {title}"
+const TEMPLATE_FIELDS: [&str; 7] = ["index", "hash", "title", "href", "tags", "user", "posted"];
+
+// Checks the fields of a list template and replaces its escape sequences
+fn parse_template(template: &str) -> Result<String> {
+  let mut rest = template;
+  while let Some(start) = rest.find('{') {
+    let Some(end) = rest[start..].find('}') else {
+      anyhow::bail!("unclosed {{");
+    };
+    let field = &rest[start + 1..start + end];
+    if !TEMPLATE_FIELDS.contains(&field) {
+      anyhow::bail!("unknown field {{{}}}, expected one of {}", field, TEMPLATE_FIELDS.join(", "));
+    }
+    rest = &rest[start + end + 1..];
+  }
+  let mut parsed = String::new();
+  let mut chars = template.chars();
+  while let Some(c) = chars.next() {
+    match (c, chars.clone().next()) {
+      ('\\', Some(escaped @ ('t' | 'n' | '\\'))) => {
+        parsed.push(match escaped {
+          't' => '\t',
+          'n' => '\n',
+          _ => '\\',
+        });
+        chars.next();
+      }
+      _ => parsed.push(c),
+    }
+  }
+  Ok(parsed)
+}
+
+// Fills the fields of the template. The tabs and new lines of the values are
+// replaced by spaces so that each bookmark stays on a line.
+fn format_bookmark(template: &str, index: usize, bookmark: &Bookmark) -> String {
+  let value = |field: &str| match field {
+    "index" => index.to_string(),
+    "hash" => bookmark.hash.clone(),
+    "title" => bookmark.title.clone(),
+    "href" => bookmark.href.clone(),
+    "tags" => bookmark.meta.tags.join(","),
+    "user" => bookmark.meta.user.clone().unwrap_or_default(),
+    "posted" => bookmark.meta.posted.map(|p| p.format("%Y-%m-%d").to_string()).unwrap_or_default(),
+    _ => String::new(),
+  };
+  let mut line = String::new();
+  let mut rest = template;
+  while let Some((before, after)) = rest.split_once('{') {
+    let (field, after) = after.split_once('}').unwrap_or((after, ""));
+    line.push_str(before);
+    line.push_str(&value(field).replace(['\t', '\n', '\r', '\0'], " "));
+    rest = after;
+  }
+  line.push_str(rest);
+  line
+}
+
+// Returns the host of the url without its www. prefix
+fn domain_of(url: &str) -> String {
+  let host = reqwest::Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default();
+  host.strip_prefix("www.").map(str::to_string).unwrap_or(host)
+}
+
+// Cuts the text to the width in columns, marking the cut with an ellipsis
+fn truncate(text: &str, width: usize) -> String {
+  if text.width() <= width {
+    return text.to_string();
+  }
+  let mut truncated = String::new();
+  let mut used = 0;
+  for c in text.chars() {
+    let c_width = c.width().unwrap_or(0);
+    if used + c_width + 1 > width {
+      break;
+    }
+    truncated.push(c);
+    used += c_width;
+  }
+  truncated.push('…');
+  truncated
+}
+
+// Prints the bookmarks as columns: position, date, title and domain. When the
+// output is a terminal, the titles are cut to fit in its width.
+fn print_columns(output: &mut impl Write, bookmarks: &[(usize, &Bookmark)], separator: char) -> Result<()> {
+  const DATE_WIDTH: usize = 10;
+  const MAX_DOMAIN_WIDTH: usize = 24;
+  let index_width = bookmarks.iter().map(|(i, _)| i.to_string().len()).max().unwrap_or(1);
+  let domains: Vec<String> =
+    bookmarks.iter().map(|(_, b)| truncate(&domain_of(&b.href), MAX_DOMAIN_WIDTH)).collect();
+  let domain_width = domains.iter().map(|d| d.width()).max().unwrap_or(0);
+  let title_width = match terminal_size::terminal_size() {
+    Some((terminal_size::Width(width), _)) => {
+      (width as usize).saturating_sub(index_width + DATE_WIDTH + domain_width + 3).max(10)
+    }
+    None => usize::MAX,
+  };
+  for ((index, bookmark), domain) in bookmarks.iter().zip(domains) {
+    let posted = bookmark.meta.posted.map(|p| p.format("%Y-%m-%d").to_string()).unwrap_or_default();
+    let title = if bookmark.title.is_empty() { &bookmark.href } else { &bookmark.title };
+    let title = truncate(&title.replace(['\t', '\n', '\r'], " "), title_width);
+    let padding = if title_width == usize::MAX { 1 } else { title_width - title.width() + 1 };
+    write!(
+      output,
+      "{:>index_width$} {:DATE_WIDTH$} {}{:padding$}{}{}",
+      index, posted, title, "", domain, separator
+    )?;
+  }
+  Ok(())
+}
+
+// Prints the bookmarks with their position in the bookmark file
+fn list(config: &Config, bookmarks: &[Bookmark], filter: &Filter, options: &ListOptions) -> Result<()> {
+  // With their position in the file
+  let mut selected: Vec<(usize, &Bookmark)> =
+    bookmarks.iter().enumerate().filter(|(_, b)| filter.matches(config, b)).map(|(i, b)| (i + 1, b)).collect();
+  match options.sort {
+    Some(SortKey::Posted) => selected.sort_by_key(|(_, b)| b.meta.posted),
+    Some(SortKey::Title) => selected.sort_by_cached_key(|(_, b)| b.title.to_lowercase()),
+    Some(SortKey::Domain) => selected.sort_by_cached_key(|(_, b)| (domain_of(&b.href), b.meta.posted)),
+    None => (),
+  }
+  if options.reverse {
+    selected.reverse();
+  }
+  let selected: Vec<(usize, &Bookmark)> =
+    selected.into_iter().skip(options.offset).take(options.limit.unwrap_or(usize::MAX)).collect();
+  let separator = if options.null { '\0' } else { '\n' };
+  let mut output = std::io::stdout().lock();
+  match &options.format {
+    Some(template) => {
+      for (index, bookmark) in selected {
+        write!(output, "{}{}", format_bookmark(template, index, bookmark), separator)?;
+      }
+    }
+    None => print_columns(&mut output, &selected, separator)?,
+  }
+  Ok(())
+}
+
+// Returns the bookmark with this hash or at this position, as printed by list
+fn find_bookmark<'a>(bookmarks: &'a [Bookmark], hash_or_index: &str) -> Option<&'a Bookmark> {
+  bookmarks.iter().find(|b| b.hash == hash_or_index).or_else(|| {
+    let index: usize = hash_or_index.parse().ok()?;
+    bookmarks.get(index.checked_sub(1)?)
+  })
+}
+
+// Opens the url with $BROWSER or the default browser of the desktop
+fn open_in_browser(url: &str) -> Result<()> {
+  let default = if cfg!(target_os = "macos") { "open" } else { "xdg-open" };
+  let browsers = env::var("BROWSER").unwrap_or(default.to_string());
+  // $BROWSER may list several browsers separated by colons
+  let browser = browsers.split(':').next().unwrap_or(default);
+  std::process::Command::new("sh")
+    .arg("-c")
+    .arg(format!("{} \"$1\"", browser))
+    .arg("sh")
+    .arg(url)
+    .stdin(std::process::Stdio::null())
+    .stdout(std::process::Stdio::null())
+    .stderr(std::process::Stdio::null())
+    .spawn()
+    .with_context(|| format!("could not run {}", browser))?;
+  Ok(())
+}
+
+// Returns the name of the user running bookmark
+fn current_user(config: &Config) -> String {
+  match &config.user {
+    Some(user) => user.clone(),
+    None => get_user_by_uid(get_current_uid())
+      .map(|user| user.name().to_string_lossy().to_string())
+      .unwrap_or_default(),
+  }
 }
 
 // Prints the url from the hash
 fn hash2url(
   config: &Config,
-  bookmarks: &Vec<Bookmark>,
+  bookmarks: &[Bookmark],
   hash: &str,
 ) -> Result<()> {
   if let Some(bookmark) = bookmarks.iter().find(|b| b.hash == hash) {
@@ -200,8 +873,11 @@ fn hash2url(
 }
 
 struct UrlStore<'a> {
+  archive: Option<Box<dyn archive::ArchiveProvider>>,
   data_folder: PathBuf,
   config: &'a Config,
+  // When the bookmarks are kept in a SQLite database, so are the embeddings
+  database: Option<SqliteStore>,
 }
 
 fn get_hash(key: &str) -> String {
@@ -217,73 +893,170 @@ fn get_hash(key: &str) -> String {
 impl<'a> UrlStore<'a> {
   fn new(config: &'a Config) -> Result<Self> {
     Ok(UrlStore {
-      data_folder: get_data_folder()?,
-      config: config,
+      archive: archive::provider(config)?,
+      data_folder: get_data_folder(config)?,
+      config,
+      database: match storage::format(config) {
+        storage::Format::Sqlite => Some(SqliteStore::open(&config.bookmarks)?),
+        _ => None,
+      },
     })
   }
 
-  fn url_to_path(self: &Self, url: &str) -> PathBuf {
-    let hash = get_hash(url);
-    let mut hashpath = self.data_folder.clone();
-    hashpath.push(&(hash.clone() + ".html"));
-    return hashpath;
+  // The directory of the files of the url, see layout.rs
+  fn url_to_dir(&self, url: &str) -> PathBuf {
+    layout::entry_dir(&self.data_folder, &get_hash(url))
+  }
+
+  fn url_to_path(&self, url: &str) -> PathBuf {
+    self.url_to_dir(url).join(layout::PAGE)
+  }
+
+  fn url_to_embeddings_path(&self, url: &str) -> PathBuf {
+    self.url_to_dir(url).join(layout::EMBEDDINGS)
+  }
+
+  // The directory holding the dated copies of the article, see snapshots.rs
+  fn url_to_snapshots_path(&self, url: &str) -> PathBuf {
+    self.url_to_dir(url).join(layout::SNAPSHOTS)
+  }
+
+  // Returns the stored copy of the article without fetching it
+  pub fn get_article(&self, url: &str) -> Result<String> {
+    Ok(std::fs::read_to_string(self.url_to_path(url))?)
+  }
+
+  // Returns the text of the stored copy of the article
+  pub fn get_text(&self, url: &str) -> Result<String> {
+    match std::fs::read_to_string(self.url_to_dir(url).join(layout::TEXT)) {
+      Ok(text) => Ok(text),
+      Err(_) => Ok(layout::page_text(&self.get_article(url)?)),
+    }
+  }
+
+  // Writes the stored copy of the article and its text
+  pub fn write_article(&self, url: &str, content: &str) -> Result<()> {
+    let dir = self.url_to_dir(url);
+    let text = layout::page_text(content);
+    layout::write_files(&dir, url, &[(layout::PAGE, content.as_bytes()), (layout::TEXT, text.as_bytes())])
   }
 
-  fn url_to_embeddings_path(self: &Self, url: &str) -> PathBuf {
-    let mut embedding_path = self.data_folder.clone();
-    embedding_path.push(&(get_hash(url) + ".html.embeddings"));
-    return embedding_path;
+  // Moves the stored files of a url to the location of another url.
+  // If the destination already has a page, it is kept with its text and
+  // embeddings and the source ones are dropped.
+  pub fn move_to(&self, from: &str, to: &str) -> Result<()> {
+    layout::move_entry(&self.url_to_dir(from), &self.url_to_dir(to), to)
   }
 
   // Check if the url is already present in the store
-  pub fn has(self: &Self, url: &str) -> bool {
+  pub fn has(&self, url: &str) -> bool {
     let hashpath = self.url_to_path(url);
-    return hashpath.exists();
+    hashpath.exists()
+  }
+
+  pub fn has_embeddings(&self, url: &str) -> bool {
+    match &self.database {
+      Some(database) => database.has_embeddings(&get_hash(url)).unwrap_or(false),
+      None => self.url_to_embeddings_path(url).exists(),
+    }
+  }
+
+  fn read_embeddings_file(&self, url: &str) -> Result<Option<Vec<f32>>> {
+    let embedding_path = self.url_to_embeddings_path(url);
+    if !embedding_path.exists() {
+      return Ok(None);
+    }
+    Ok(Some(layout::decode_embeddings(&std::fs::read(&embedding_path)?)?))
+  }
+
+  fn write_embeddings_file(&self, url: &str, embeddings: &[f32]) -> Result<()> {
+    let embeddings = layout::encode_embeddings(embeddings);
+    layout::write_files(&self.url_to_dir(url), url, &[(layout::EMBEDDINGS, &embeddings)])
+  }
+
+  // Returns the embeddings of the article if they were computed
+  pub fn load_embeddings(&self, url: &str) -> Result<Option<Vec<f32>>> {
+    match &self.database {
+      Some(database) => database.load_embeddings(&get_hash(url)),
+      None => self.read_embeddings_file(url),
+    }
   }
 
-  pub fn has_embeddings(self: &Self, url: &str) -> bool {
-    let hashpath = self.url_to_embeddings_path(url);
-    return hashpath.exists();
+  pub fn save_embeddings(&self, url: &str, embeddings: &[f32]) -> Result<()> {
+    match &self.database {
+      Some(database) => database.save_embeddings(&get_hash(url), embeddings),
+      None => self.write_embeddings_file(url, embeddings),
+    }
   }
 
   // Fetch the content from the internet whatever the store status
-  pub fn fetch_url(self: &Self, url: &str) -> Result<String> {
+  pub fn fetch_url(&self, url: &str) -> Result<String> {
     fetch_http(self.config, url)
   }
 
   // Will check if the article is in the store, fetch the article otherwise
   // store it if configured so add a search index if configured so.
-  pub fn fetch_article(self: &Self, url: &str) -> Result<String> {
+  pub fn fetch_article(&self, url: &str) -> Result<String> {
     let hashpath = self.url_to_path(url);
-    let search_enabled = self.config.search.unwrap_or(false);
     // Check the presence of the content of the url in the data folder
     let content = std::fs::read_to_string(&hashpath).or_else(|_| {
-      let content = fetch_http(self.config, url)?;
-      if self.config.store_articles.unwrap_or(false) || search_enabled {
-        // Save the content in a file in the data folder
-        match std::fs::write(&hashpath, &content) {
-          Ok(_) => println!("{} saved", url),
-          Err(e) => anyhow::bail!("error writing to {} ({})", &hashpath.to_string_lossy(), e),
-        }
-      }
+      let content = fetch_http(self.config, url).or_else(|e| self.fetch_from_archive(url, e))?;
+      self.store_article(url, &content)?;
       Ok::<std::string::String, anyhow::Error>(content)
     })?;
     Ok(content)
   }
 
-  pub fn compute_embeddings(self: &Self, url: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
-    // Create the embedding file path
-    let embedding_path = self.url_to_embeddings_path(url);
-    if !embedding_path.exists() {
+  // Saves the content in a file in the data folder if configured so
+  pub fn store_article(&self, url: &str, content: &str) -> Result<()> {
+    if self.config.store_articles.unwrap_or(false) || self.config.search.unwrap_or(false) {
+      self.write_article(url, content)?;
+      println!("{} saved", url);
+    }
+    Ok(())
+  }
+
+  // Recomputes the embeddings of a bookmark, after its notes or its page changed
+  pub fn reindex(&self, embedder: &Embedder, bookmark: &Bookmark) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let content = self.get_article(&bookmark.href).unwrap_or_default();
+    let embeddings = embedder.embed(&notes::index_text(bookmark, &content))?;
+    self.save_embeddings(&bookmark.href, &embeddings.to_vec1::<f32>()?)?;
+    Ok(())
+  }
+
+  // Falls back to the archived copy of a page which could not be fetched.
+  // Returns the original error if there is none.
+  fn fetch_from_archive(&self, url: &str, error: anyhow::Error) -> Result<String> {
+    let Some(archive) = &self.archive else {
+      return Err(error);
+    };
+    match archive.fetch(url) {
+      Ok(Some(content)) => {
+        eprintln!("warning: could not fetch {} ({}), using the copy of {}", url, error, archive.name());
+        Ok(content)
+      }
+      Ok(None) => Err(error),
+      Err(e) => Err(anyhow::anyhow!("{} and {} failed: {}", error, archive.name(), e)),
+    }
+  }
+
+  // Submits the page to the archive if configured so
+  pub fn submit_to_archive(&self, url: &str) {
+    if let Some(archive) = self.archive.as_ref().filter(|_| archive::submit_enabled(self.config)) {
+      if let Err(e) = archive.submit(url) {
+        eprintln!("warning: could not submit {} to {} ({})", url, archive.name(), e);
+      }
+    }
+  }
+
+  pub fn compute_embeddings(&self, url: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !self.has_embeddings(url) {
       // Compute the embeddings of the file
       // FIXME: get rid of unwrap
-      let embeddings = compute_embeddings(&content)?;
+      let embeddings = compute_embeddings(content)?;
       // Convert to an array of f32
       let array: Vec<f32> = embeddings.to_vec1()?;
-      // Serialize the array to the file
-      let file = std::fs::File::create(embedding_path)?;
-      let mut writer = std::io::BufWriter::new(file);
-      serde_json::to_writer(&mut writer, &array)?;
+      self.save_embeddings(url, &array)?;
     }
     Ok(())
   }
@@ -315,7 +1088,9 @@ fn get_hn_article(url_store: &UrlStore, url: &str) -> Result<(String, scraper::H
 }
 
 // Fetch the url (of in case of an HN article the original article) and return
-// the article url and the title
+// the article url and the title.
+// If configured so, the article url is the one provided by the page's
+// <link rel="canonical">.
 fn fetch_article(url_store: &UrlStore, url: &str) -> Result<(String, String)> {
   let _ = std::io::stdout().flush();
   // If the url if from an hacker new post, fetch the original article
@@ -326,6 +1101,19 @@ fn fetch_article(url_store: &UrlStore, url: &str) -> Result<(String, String)> {
     let body = url_store.fetch_article(url)?;
     (url.to_string(), Html::parse_document(&body))
   };
+  read_article(url_store, article_url, &document)
+}
+
+// Returns the article url and the title of a page, see fetch_article
+fn read_article(url_store: &UrlStore, article_url: String, document: &Html) -> Result<(String, String)> {
+  let article_url = match canonical_link(&article_url, document) {
+    Some(link) if canonical::use_canonical_link(url_store.config) => {
+      // The article was stored under the url it was fetched from
+      url_store.move_to(&article_url, &link)?;
+      link
+    }
+    _ => article_url,
+  };
   let selector = Selector::parse(r#"title"#).unwrap();
   // Get the title
   if let Some(title_element) = document.select(&selector).next() {
@@ -343,71 +1131,329 @@ fn fetch_article(url_store: &UrlStore, url: &str) -> Result<(String, String)> {
   }
 }
 
+fn warn_already_present(bookmark: &Bookmark) {
+  eprint!(
+    "warning: this url is already present in bookmarks: {}",
+    bookmark.title
+  );
+  if let Some(date) = bookmark.meta.posted {
+    eprint!(" added the {}", date);
+  }
+  eprintln!();
+}
+
+// The outcome of adding a url
+#[derive(PartialEq)]
+enum AddOutcome {
+  Added,
+  AlreadyPresent,
+  // The page could not be fetched in strict mode
+  Failed,
+}
+
 // Adds a bookmark based on a URL
 // The function will treat hacker news stories differently as it will consider
 // them as referer and the article pointer to as the original submission.
+// The url is fetched and kept as given, its canonical form is only compared to
+// the existing bookmarks.
+// The new bookmark is pushed at the end of the list, the caller writes it with
+// storage::append_bookmarks.
 fn add(
   config: &Config,
   url_store: &UrlStore,
   bookmarks: &mut Vec<Bookmark>,
   url: &str,
-) -> Result<()> {
+  options: &AddOptions,
+) -> Result<AddOutcome> {
   // Check the url is not already present
-  if let Some(result) = bookmarks.iter().find(|b| b.href == *url) {
-    eprint!(
-      "warning: this url is already present in bookmarks: {}",
-      result.title
-    );
-    if let Some(date) = result.meta.posted {
-      eprint!(" added the {}", date);
+  let is_present = |url: &str| {
+    let url = canonicalize(config, url);
+    bookmarks.iter().find(|b| canonicalize(config, &b.href) == url).cloned()
+  };
+  if let Some(result) = is_present(url) {
+    warn_already_present(&result);
+    return Ok(AddOutcome::AlreadyPresent);
+  }
+  // The article url will be different from the url if the url is from
+  // Hacker News. We will bookmark the article url and only keep the url
+  // as a referer
+  let mut is_hacker_news = false;
+  let mut article_url = url.to_string();
+  let mut title = "".to_string();
+  let mut fetch = None;
+  if !options.no_fetch {
+    let result = match &options.html {
+      Some(html) => url_store
+        .store_article(url, html)
+        .and_then(|_| read_article(url_store, url.to_string(), &Html::parse_document(html))),
+      None => {
+        print!("fetching {}... ", url);
+        fetch_article(url_store, url)
+      }
+    };
+    fetch = Some(FetchStatus::new(&result));
+    match result {
+      Ok((new_article_url, fetched_title)) => {
+        is_hacker_news = url.contains("news.ycombinator.com/item?id=");
+        article_url = new_article_url;
+        title = fetched_title;
+      }
+      Err(e) if options.strict => {
+        print!("\r\x1b[0K");
+        let _ = std::io::stdout().flush();
+        eprintln!("{}", serde_json::json!({ "url": url, "error": e.to_string() }));
+        return Ok(AddOutcome::Failed);
+      }
+      Err(_) => eprintln!("warning: could not fetch {}", article_url),
     }
-    eprintln!("");
-  } else {
-    print!("fetching {}... ", url);
-    // The article url will be different from the url if the url is from
-    // Hacker News. We will bookmark the article url and only keep the url
-    // as a referer
-    let mut is_hacker_news = false;
-    let mut article_url = url.to_string();
-    let mut title = "".to_string();
-    if let Ok((new_article_url, fetched_title)) = fetch_article(&url_store, &url) {
-      is_hacker_news = url != article_url;
-      article_url = new_article_url;
-      title = fetched_title;
-    } else {
-      eprintln!("warning: could not fetch {}", article_url);
-    }
-    let user = get_user_by_uid(get_current_uid()).unwrap();
-    // Create the new bookmark and add it to the list
-    bookmarks.push(Bookmark {
-      hash: get_hash(&article_url),
-      href: article_url.clone(),
-      title: title.clone(),
-      meta: Metadata {
-        posted: Some(chrono::offset::Utc::now().naive_utc()),
-        user: Some(user.name().to_string_lossy().to_string()),
-        referer: if is_hacker_news {
-          Some(url.to_string())
-        } else {
-          None
-        },
+  }
+  // The article url may be an already bookmarked one
+  if article_url != *url {
+    if let Some(result) = is_present(&article_url) {
+      print!("\r\x1b[0K");
+      let _ = std::io::stdout().flush();
+      warn_already_present(&result);
+      return Ok(AddOutcome::AlreadyPresent);
+    }
+  }
+  if let Some(option_title) = &options.title {
+    title = option_title.clone();
+  }
+  // Create the new bookmark and add it to the list
+  bookmarks.push(Bookmark {
+    hash: get_hash(&article_url),
+    href: article_url.clone(),
+    title: title.clone(),
+    meta: Metadata {
+      posted: Some(options.posted.unwrap_or_else(|| chrono::offset::Utc::now().naive_utc())),
+      user: Some(current_user(config)),
+      referer: if is_hacker_news {
+        Some(url.to_string())
+      } else {
+        None
       },
-    });
-    // Write the bookmark file
-    write_bookmarks(bookmarks, &config.bookmarks)?;
+      referers: vec![],
+      tags: options.tags.clone(),
+      fetch,
+      read: None,
+      starred: false,
+      archived: false,
+      notes: options.note.clone(),
+      highlights: vec![],
+      link: None,
+      extra: Default::default(),
+    },
+    extra: Default::default(),
+  });
+  print!("\radded {}", if !title.is_empty() { title } else { article_url.clone() });
+  println!("\x1b[0K");
+  url_store.submit_to_archive(&article_url);
+  Ok(AddOutcome::Added)
+}
+
+// Parses a date given on the command line, either a day or a date and time
+fn parse_date(date: &str) -> Result<NaiveDateTime> {
+  match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+    Ok(day) => Ok(day.and_hms_opt(0, 0, 0).unwrap()),
+    Err(_) => NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S")
+      .or_else(|_| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S"))
+      .context("expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS"),
+  }
+}
 
-    print!("\radded {}", if title != "" { title } else { article_url });
-    println!("\x1b[0K");
+// Parses the end of a date range: a day includes the whole day
+fn parse_until(date: &str) -> Result<NaiveDateTime> {
+  match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+    Ok(day) => Ok(day.and_hms_nano_opt(23, 59, 59, 999_999_999).unwrap()),
+    Err(_) => parse_date(date),
   }
+}
+
+// Applies a change to the metadata of the bookmarks with the provided hashes.
+// Returns the changed bookmarks.
+fn update<'a>(
+  bookmarks: &'a mut [Bookmark],
+  hashes: &[String],
+  change: impl Fn(&mut Metadata),
+) -> Vec<&'a Bookmark> {
+  for hash in hashes {
+    if !bookmarks.iter().any(|b| b.hash == *hash) {
+      eprintln!("hash not found {}", hash);
+    }
+  }
+  bookmarks
+    .iter_mut()
+    .filter(|b| hashes.contains(&b.hash))
+    .map(|bookmark| {
+      change(&mut bookmark.meta);
+      &*bookmark
+    })
+    .collect()
+}
+
+// Removes the bookmarks with the provided hashes and returns them.
+// The stored articles are kept.
+fn remove(bookmarks: &mut Vec<Bookmark>, hashes: &[String]) -> Vec<Bookmark> {
+  let mut removed = vec![];
+  for hash in hashes {
+    if let Some(index) = bookmarks.iter().position(|b| b.hash == *hash) {
+      let bookmark = bookmarks.remove(index);
+      println!("removed {} ({})", bookmark.title, bookmark.href);
+      removed.push(bookmark);
+    } else {
+      eprintln!("hash not found {}", hash);
+    }
+  }
+  removed
+}
+
+// Rewrites the bookmark hrefs to their canonical form. The stored articles and
+// embeddings are moved to the location of the new hash. When the same
+// canonical url ends up in several bookmarks, dedup will merge them.
+// The stored article is used to look for a <link rel="canonical">, the url is
+// never fetched.
+fn canonicalize_bookmarks(
+  config: &Config,
+  url_store: &UrlStore,
+  bookmarks: &mut [Bookmark],
+  dry_run: bool,
+) -> Result<usize> {
+  let mut count = 0;
+  for bookmark in bookmarks.iter_mut() {
+    let mut href = canonicalize(config, &bookmark.href);
+    if canonical::use_canonical_link(config) {
+      if let Ok(content) = url_store.get_article(&bookmark.href) {
+        if let Some(link) = canonical_link(&href, &Html::parse_document(&content)) {
+          href = canonicalize(config, &link);
+        }
+      }
+    }
+    if href != bookmark.href {
+      println!("{} -> {}", bookmark.href, href);
+      if !dry_run {
+        url_store.move_to(&bookmark.href, &href)?;
+        bookmark.hash = get_hash(&href);
+        bookmark.href = href;
+      }
+      count += 1;
+    }
+  }
+  Ok(count)
+}
+
+// Copies the bookmarks and their embeddings to a new bookmark file using
+// another storage backend. The current bookmark file is left untouched.
+fn migrate(
+  config: &Config,
+  url_store: &UrlStore,
+  bookmarks: &[Bookmark],
+  to: storage::Backend,
+  output: Option<PathBuf>,
+) -> Result<()> {
+  let (format, extension) = match to {
+    storage::Backend::Sqlite => (storage::Format::Sqlite, "db"),
+    storage::Backend::Json => (storage::Format::Json, "json"),
+  };
+  let output = output.unwrap_or_else(|| config.bookmarks.with_extension(extension));
+  if output.exists() {
+    anyhow::bail!("{} already exists", output.display());
+  }
+  let backups = config.backups.unwrap_or(storage::DEFAULT_BACKUPS);
+  // Nothing is left behind when a bookmark cannot be copied, e.g. a duplicate
+  if let Err(e) = storage::open_store(&output, format, backups).and_then(|store| store.save(bookmarks)) {
+    let _ = std::fs::remove_file(&output);
+    return Err(e);
+  }
+  // The embeddings follow the bookmarks into the database or out of it
+  let database = match to {
+    storage::Backend::Sqlite => Some(SqliteStore::open(&output)?),
+    storage::Backend::Json => None,
+  };
+  let mut count = 0;
+  for bookmark in bookmarks {
+    if let Some(embeddings) = url_store.load_embeddings(&bookmark.href)? {
+      match &database {
+        Some(database) => database.save_embeddings(&bookmark.hash, &embeddings)?,
+        None => url_store.write_embeddings_file(&bookmark.href, &embeddings)?,
+      }
+      count += 1;
+    }
+  }
+  println!(
+    "migrated {} bookmarks and {} embeddings to {}",
+    bookmarks.len(),
+    count,
+    output.display()
+  );
+  println!("update the 'bookmarks' entry of your configuration to use it");
+  Ok(())
+}
+
+fn hrefs_by_hash(bookmarks: &[Bookmark]) -> HashMap<String, String> {
+  bookmarks.iter().map(|b| (b.hash.clone(), b.href.clone())).collect()
+}
+
+// Moves the files of the data folder from the flat layout of the previous
+// versions to the directories of the bookmarks, see layout.rs
+fn shard_data_folder(url_store: &UrlStore, bookmarks: &[Bookmark]) -> Result<()> {
+  let count = layout::migrate(&url_store.data_folder, &hrefs_by_hash(bookmarks))?;
+  println!("moved {} files to the new layout of {}", count, url_store.data_folder.display());
   Ok(())
 }
 
-fn get_data_folder() -> Result<PathBuf> {
+// The folder all the bookmark files shared before each got its own
+fn get_shared_data_folder() -> Result<PathBuf> {
   let default_config_data_path: String =
     env::var("XDG_DATA_HOME").unwrap_or(env::var("HOME")? + "/.local/share") + "/bookmark/";
-  std::fs::create_dir_all(&default_config_data_path)?;
-  let path = std::path::PathBuf::from(&default_config_data_path);
-  return Ok(path);
+  Ok(std::path::PathBuf::from(&default_config_data_path))
+}
+
+// The folder of the stored pages and the embeddings of the bookmark file: the
+// configured one or a folder of the shared one named after the bookmark file.
+// The extension of the file is left out of the name so that the folder stays
+// the same after migrating to another backend.
+fn get_data_folder(config: &Config) -> Result<PathBuf> {
+  let path = match &config.data_dir {
+    Some(data_dir) => config.bookmarks.parent().unwrap_or(std::path::Path::new("")).join(data_dir),
+    None => {
+      let file = std::path::absolute(config.bookmarks.with_extension(""))?;
+      let name = file.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+      let hash = get_hash(&file.to_string_lossy());
+      get_shared_data_folder()?.join(format!("{}-{}", name, &hash[..8]))
+    }
+  };
+  if !path.exists() {
+    std::fs::create_dir_all(&path)?;
+    let shared = get_shared_data_folder()?;
+    if layout::flat_files(&shared).is_ok_and(|files| !files.is_empty()) {
+      eprintln!(
+        "note: the pages stored before are in {}, `bookmark migrate --split-data` copies the ones of {} to {}",
+        shared.display(),
+        config.bookmarks.display(),
+        path.display()
+      );
+    }
+  }
+  layout::init(&path)?;
+  Ok(path)
+}
+
+// Copies the files of the bookmarks from the folder shared by the bookmark
+// files to the one of this bookmark file. They are copied, not moved, as the
+// other bookmark files may have some of the urls.
+fn split_data_folder(url_store: &UrlStore, bookmarks: &[Bookmark]) -> Result<()> {
+  let shared = get_shared_data_folder()?;
+  if std::path::absolute(&shared)? == std::path::absolute(&url_store.data_folder)? {
+    anyhow::bail!("{} is the data folder of {}", shared.display(), url_store.config.bookmarks.display());
+  }
+  let hrefs = hrefs_by_hash(bookmarks);
+  let count = layout::import_flat(&shared, &url_store.data_folder, &hrefs, |hash| hrefs.contains_key(hash), true)?;
+  println!("copied {} files from {} to {}", count, shared.display(), url_store.data_folder.display());
+  println!(
+    "once every bookmark file is migrated, the files left in {} can be deleted",
+    shared.display()
+  );
+  Ok(())
 }
 
 fn get_state_folder() -> Result<PathBuf> {
@@ -415,7 +1461,7 @@ fn get_state_folder() -> Result<PathBuf> {
     env::var("XDG_STATE_HOME").unwrap_or(env::var("HOME")? + "/.local/state") + "/bookmark/";
   std::fs::create_dir_all(&default_config_state_path)?;
   let path = std::path::PathBuf::from(&default_config_state_path);
-  return Ok(path);
+  Ok(path)
 }
 
 // Checks if chromium is available in headless mode with the dump-dom option.
@@ -430,7 +1476,7 @@ fn chromium_available(config: &Config) -> bool {
       return false;
     }
     if let Some(chromium_path) = &chromium_config.path {
-      &chromium_path
+      chromium_path
     } else {
       "chromium"
     }
@@ -445,8 +1491,8 @@ fn chromium_available(config: &Config) -> bool {
       let mut reader = std::io::BufReader::new(state_file);
       let mut line = String::new();
       let _ = reader.read_line(&mut line);
-      if line.len() > 0 {
-        return line.chars().nth(0).unwrap() == '1';
+      if let Some(state) = line.chars().next() {
+        return state == '1';
       }
     }
   }
@@ -455,13 +1501,7 @@ fn chromium_available(config: &Config) -> bool {
     .args(["--headless", "--dump-dom", "www.google.com"])
     .output()
   {
-    Ok(output) => {
-      if output.status.success() {
-        true
-      } else {
-        false
-      }
-    }
+    Ok(output) => output.status.success(),
     Err(_) => false,
   };
   // Record the chromium state in a file
@@ -471,7 +1511,7 @@ fn chromium_available(config: &Config) -> bool {
       let _ = state_file.write_all(if available { b"1\n" } else { b"0\n" });
     }
   }
-  return available;
+  available
 }
 
 // Fetches the page through chromium so that javascript can be interpreted if needed.
@@ -496,43 +1536,61 @@ fn fetch_by_chromium(url: &str) -> Result<String> {
 use candle_core::{Device, Tensor};
 
 // from https://github.com/huggingface/candle/blob/26c16923b92bddda6b05ee1993af47fb6de6ebd7/candle-examples/examples/bert/main.rs
-fn compute_embeddings(content: &str) -> Result<Tensor, Box<dyn Error + Send + Sync>> {
-  use candle_nn::VarBuilder;
-  use candle_transformers::models::bert::{BertModel, Config, DTYPE};
-  use tokenizers::Tokenizer;
-
-  let device = &Device::Cpu;
-  let mut tokenizer_builder = Tokenizer::from_file("./all-MiniLM-L6-v2/tokenizer.json")?;
-  let config = std::fs::read_to_string("./all-MiniLM-L6-v2/config.json")?;
-  let config: Config = serde_json::from_str(&config)?;
-  let vb = VarBuilder::from_pth("./all-MiniLM-L6-v2/pytorch_model.bin", DTYPE, device)?;
-  let model = BertModel::load(vb, &config)?;
-  // let start = std::time::Instant::now();
-  let tokenizer = tokenizer_builder
-    .with_padding(None)
-    .with_truncation(None)
-    .map_err(E::msg)?;
-  let tokens = tokenizer
-    .encode(content, true)
-    .map_err(E::msg)?
-    .get_ids()
-    .to_vec();
-
-  let token_ids = Tensor::new(tokens.as_slice(), device)?.unsqueeze(0)?;
-  let token_type_ids = token_ids.zeros_like()?;
-  // println!("Loaded and encoded {:?}", start.elapsed());
-
-  // let start = std::time::Instant::now();
-  let embeddings = model.forward(&token_ids, &token_type_ids, None)?;
-  // This will give as an embedding per token so we apply some avg-pooling by
-  // taking the mean embedding value for all tokens (including padding)
-  let (_n_sentence, n_tokens, _hidden_size) = embeddings.dims3()?;
-  let embeddings = (embeddings.sum(1)? / (n_tokens as f64))?;
+// The model computing the embeddings, loaded once for all the texts to embed
+struct Embedder {
+  model: candle_transformers::models::bert::BertModel,
+  tokenizer: tokenizers::Tokenizer,
+}
+
+impl Embedder {
+  fn new() -> Result<Self, Box<dyn Error + Send + Sync>> {
+    use candle_nn::VarBuilder;
+    use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+    use tokenizers::Tokenizer;
+
+    let device = &Device::Cpu;
+    let mut tokenizer = Tokenizer::from_file("./all-MiniLM-L6-v2/tokenizer.json")?;
+    let config = std::fs::read_to_string("./all-MiniLM-L6-v2/config.json")?;
+    let config: Config = serde_json::from_str(&config)?;
+    let vb = VarBuilder::from_pth("./all-MiniLM-L6-v2/pytorch_model.bin", DTYPE, device)?;
+    let model = BertModel::load(vb, &config)?;
+    tokenizer
+      .with_padding(None)
+      .with_truncation(None)
+      .map_err(E::msg)?;
+    Ok(Embedder { model, tokenizer })
+  }
+
+  fn embed(&self, content: &str) -> Result<Tensor, Box<dyn Error + Send + Sync>> {
+    let device = &Device::Cpu;
+    // let start = std::time::Instant::now();
+    let tokens = self
+      .tokenizer
+      .encode(content, true)
+      .map_err(E::msg)?
+      .get_ids()
+      .to_vec();
+
+    let token_ids = Tensor::new(tokens.as_slice(), device)?.unsqueeze(0)?;
+    let token_type_ids = token_ids.zeros_like()?;
+    // println!("Loaded and encoded {:?}", start.elapsed());
+
+    // let start = std::time::Instant::now();
+    let embeddings = self.model.forward(&token_ids, &token_type_ids, None)?;
+    // This will give as an embedding per token so we apply some avg-pooling by
+    // taking the mean embedding value for all tokens (including padding)
+    let (_n_sentence, n_tokens, _hidden_size) = embeddings.dims3()?;
+    let embeddings = (embeddings.sum(1)? / (n_tokens as f64))?;
     // from dimension [1, 384] to [384]
-  let embeddings = embeddings.squeeze(0)?;
-  // println!("Took {:?}", start.elapsed());
+    let embeddings = embeddings.squeeze(0)?;
+    // println!("Took {:?}", start.elapsed());
 
-  Ok(embeddings)
+    Ok(embeddings)
+  }
+}
+
+fn compute_embeddings(content: &str) -> Result<Tensor, Box<dyn Error + Send + Sync>> {
+  Embedder::new()?.embed(content)
 }
 
 fn similarity(e_i: Tensor, e_j: Tensor) -> Result<f32> {
@@ -540,73 +1598,89 @@ fn similarity(e_i: Tensor, e_j: Tensor) -> Result<f32> {
   let sum_i2 = (&e_i * &e_i)?.sum_all()?.to_scalar::<f32>()?;
   let sum_j2 = (&e_j * &e_j)?.sum_all()?.to_scalar::<f32>()?;
   let cosine_similarity = sum_ij / (sum_i2 * sum_j2).sqrt();
-  return Ok(cosine_similarity);
+  Ok(cosine_similarity)
 }
 
 // reference: https://www.reddit.com/r/rust/comments/1hyfex8/comment/m6kce24/
-fn search(config: &Config, bookmarks: &Vec<Bookmark>, needle: &Vec<String>) -> Result<(), Box<dyn Error + Send + Sync>> {
-  let needle = needle.join(" ");
-
-  let needle_embeddings = compute_embeddings(&needle)?;
+// Returns the bookmarks sorted by decreasing similarity with the needle along
+// with their similarity. The bookmarks without embeddings are left out.
+fn semantic_search<'a>(
+  embedder: &Embedder,
+  bookmarks: &[&'a Bookmark],
+  needle: &str,
+  load_embeddings: impl Fn(&Bookmark) -> Result<Option<Vec<f32>>>,
+) -> Result<Vec<(f32, &'a Bookmark)>, Box<dyn Error + Send + Sync>> {
+  let needle_embeddings = embedder.embed(needle)?;
   // println!(">{needle_embeddings}");
 
-  // Retrieve all the path in the data folder that ends with .embeddings
-  let embedding_paths = std::fs::read_dir(get_data_folder()?)?
-    .filter(|r| r.is_ok()) // Get rid of Err variants for Result<DirEntry>
-    .map(|r| r.unwrap()) // This is safe, since we only have the Ok variants
-    .filter(|dir_entry| {
-      if let Ok(file_type) = dir_entry.file_type() {
-        file_type.is_file()
-      } else { false }
-    })
-    .filter(|dir_entry| {
-      if let Some(extension) = dir_entry.path().extension() {
-        extension == "embeddings"
-      } else { false }
-    })
-    .map(|dir_entry| dir_entry.path());
-
-  let mut similarities = embedding_paths
-    .map(|embedding_path| {
-      let inputfile = std::fs::File::open(&embedding_path)?;
-      let article_embeddings: Vec<f32> = serde_json::from_reader(inputfile)?;
+  let mut similarities = bookmarks
+    .iter()
+    .map(|bookmark| {
+      let Some(article_embeddings) = load_embeddings(bookmark)? else {
+        return Ok(None);
+      };
       let length = article_embeddings.len();
       let article_embeddings = Tensor::from_vec(article_embeddings, length, &Device::Cpu)?;
       let similarity = similarity(needle_embeddings.clone(), article_embeddings)?;
-      Ok::<(f32, PathBuf), E>((similarity, embedding_path))
+      Ok::<Option<(f32, &Bookmark)>, E>(Some((similarity, *bookmark)))
     })
-    .filter(|r| r.is_ok()) // Get rid of Err variants for Result<DirEntry>
-    .map(|r| r.unwrap()) // This is safe, since we only have the Ok variants
+    .filter_map(|r| r.ok().flatten()) // Get rid of the embeddings we could not read
     .collect::<Vec<_>>();
-  similarities.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+  similarities.sort_by(|a, b| b.0.total_cmp(&a.0));
+  Ok(similarities)
+}
 
-  for entry in similarities.iter().take(5) {
-    let embedding_path = &entry.1;
-    let hash = embedding_path.file_stem().unwrap();
-    if let Some(bookmark) = bookmarks.iter().find(|b| hash.to_str().unwrap().starts_with(&b.hash)) {
-      println!("{} {}", entry.0, bookmark.href);
-    }
+fn search(config: &Config, url_store: &UrlStore, bookmarks: &[&Bookmark], needle: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let similarities = semantic_search(&Embedder::new()?, bookmarks, &needle.join(" "), |bookmark| {
+    url_store.load_embeddings(&bookmark.href)
+  })?;
+  for (similarity, bookmark) in similarities.iter().take(5) {
+    println!("{} {}", similarity, bookmark.href);
   }
 
   Ok(())
 }
 
+// Returns the bookmarks whose title, url, tags, notes, highlights or stored
+// article contain all the words of the needle, ignoring case.
+fn keyword_search<'a>(url_store: &UrlStore, bookmarks: &[&'a Bookmark], needle: &[String]) -> Vec<&'a Bookmark> {
+  let words: Vec<String> = needle.iter().flat_map(|n| n.split_whitespace()).map(str::to_lowercase).collect();
+  let mut matches = vec![];
+  for bookmark in bookmarks {
+    let article = url_store.get_text(&bookmark.href).unwrap_or_default();
+    let text = format!(
+      "{} {} {} {}",
+      bookmark.title,
+      bookmark.href,
+      bookmark.meta.tags.join(" "),
+      notes::index_text(bookmark, &article)
+    )
+    .to_lowercase();
+    if words.iter().all(|word| text.contains(word)) {
+      matches.push(*bookmark);
+    }
+  }
+  matches
+}
+
 // Go through the article and check their respect the configuration
-fn check_fetch(config: &Config, url_store: &UrlStore, bookmarks: &Vec<Bookmark>) -> Result<(), Box<dyn Error + Send + Sync>> {
+// The fetch status of the bookmarks is updated. Returns whether some were.
+fn check_fetch(config: &Config, url_store: &UrlStore, bookmarks: &mut [Bookmark]) -> Result<bool, Box<dyn Error + Send + Sync>> {
   let mut warn = false;
-  for bookmark in bookmarks {
+  for bookmark in bookmarks.iter_mut() {
     if !url_store.has(&bookmark.href) {
       if !warn {
         warn = true;
         println!("some articles are missing from the local disks, please wait while they are being fetched...");
       }
-      match url_store.fetch_article(&bookmark.href) {
-        Err(e) => eprintln!("error: could not fetch {} ({})", bookmark.href, e),
-        _ => (),
+      let result = url_store.fetch_article(&bookmark.href);
+      if let Err(e) = &result {
+        eprintln!("error: could not fetch {} ({})", bookmark.href, e);
       }
+      bookmark.meta.fetch = Some(FetchStatus::new(&result));
     }
   }
-  Ok(())
+  Ok(warn)
 }
 
 fn check_embeddings(config: &Config, url_store: &UrlStore, bookmarks: &Vec<Bookmark>) -> Result<(), Box<dyn Error + Send + Sync>> {
@@ -619,9 +1693,9 @@ fn check_embeddings(config: &Config, url_store: &UrlStore, bookmarks: &Vec<Bookm
       }
       if config.search.unwrap_or(false) {
         if let Ok(content) = url_store.fetch_article(&bookmark.href) {
-          match url_store.compute_embeddings(&bookmark.href, &content) {
-            Err(e) => eprintln!("error: could not index {} ({})", bookmark.href, e),
-            _ => (),
+          let text = notes::index_text(bookmark, &content);
+          if let Err(e) = url_store.compute_embeddings(&bookmark.href, &text) {
+            eprintln!("error: could not index {} ({})", bookmark.href, e);
           }
         }
       }
@@ -674,75 +1748,453 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
   let mut config: Config = serde_yaml::from_str(&config_file)?;
   if let Some(bookmarks) = opt.bookmarks {
     config.bookmarks = std::path::PathBuf::from(&bookmarks);
+    // An absolute data folder belongs to the configured bookmark file
+    if config.data_dir.as_ref().is_some_and(|data_dir| data_dir.is_absolute()) {
+      config.data_dir = None;
+    }
   }
 
+  // The merge driver works on the files git gives it, not the bookmark file
+  if let Some(Commands::Merge { base, ours, theirs }) = &opt.command {
+    let conflicts = merge::merge_files(&config, base, ours, theirs)?;
+    for conflict in &conflicts {
+      eprintln!("conflict: {}", conflict);
+    }
+    if !conflicts.is_empty() {
+      std::process::exit(1);
+    }
+    return Ok(());
+  }
+
+  // The server, the native messaging host and the terminal interface lock the
+  // bookmark file for each change
+  if let Some(Commands::Tui {}) = &opt.command {
+    tui::run(&config)?;
+    return Ok(());
+  }
+  if let Some(Commands::NativeHost { .. }) = &opt.command {
+    native::run(&config)?;
+    return Ok(());
+  }
+  if let Some(Commands::Serve { listen }) = &opt.command {
+    let server_config = config.server.as_ref();
+    let listen = listen
+      .clone()
+      .or_else(|| server_config.and_then(|s| s.listen.clone()))
+      .unwrap_or(server::DEFAULT_LISTEN.to_string());
+    let token = server_config.and_then(|s| s.token.clone());
+    server::serve(&config, &listen, token)?;
+    return Ok(());
+  }
+
+  // The lock is held until the end of the execution so that nobody modifies
+  // the bookmark file between the moment we read it and the moment we write it
+  let _lock = storage::lock(&config.bookmarks)?;
   // Load the bookmark files or create it if it does not exists
-  let mut bookmarks: Vec<Bookmark> = {
-    let inputfile = match std::fs::OpenOptions::new()
-      .read(true)
-      .write(true)
-      .create(true)
-      .open(&config.bookmarks) {
-      Ok(inputfile) => inputfile,
-      Err(e) => {
-        eprintln!("{}: {}", config.bookmarks.display(), e);
-        std::process::exit(1);
-      }
-    };
-    match inputfile.metadata() {
-      // serde does not accpet empty files
-      Ok(metadata) if metadata.len() == 0 => vec![],
-      Ok(metadata) => match serde_json::from_reader(std::io::BufReader::new(inputfile)) {
-        Ok(json) => json,
-        Err(e) => {
-          eprintln!(
-            "{}: could not parse json file: {}",
-            config.bookmarks.display(),
-            e
-          );
-          std::process::exit(1);
-        }
+  let (version, mut bookmarks) = match storage::open(&config).and_then(|store| store.load_versioned()) {
+    Ok(loaded) => loaded,
+    Err(e) => {
+      eprintln!("{}: {:#}", config.bookmarks.display(), e);
+      std::process::exit(1);
+    }
+  };
+  // What was done to the bookmark file, used as the git commit message
+  let mut history: Vec<String> = vec![];
+  // Whether the command failed after changing the bookmark file
+  let mut failed = false;
+  // Everytime bookmark runs, it remove duplicates. The file is only written if
+  // something changed. The dedup command does it itself to report the merges
+  // and migrate leaves the file as it is until it upgrades or copies it.
+  if !matches!(
+    opt.command,
+    Some(Commands::Dedup { .. }) | Some(Commands::Canonicalize { dry_run: true }) | Some(Commands::Migrate { .. })
+  ) {
+    let result = dedup(&config, &bookmarks);
+    if result.changed {
+      if result.bookmarks.len() < bookmarks.len() {
+        println!("deduped {} entries", bookmarks.len() - result.bookmarks.len());
       }
-      Err(e) => {
-        eprintln!("{}: {}", config.bookmarks.display(), e);
-        std::process::exit(1);
+      history.push(format!("dedup: merged {} entries", bookmarks.len() - result.bookmarks.len()));
+      bookmarks = result.bookmarks;
+      write_bookmarks(&config, &bookmarks)?;
+      if version < schema::CURRENT_VERSION {
+        println!(
+          "upgraded {} from version {} to version {}",
+          config.bookmarks.display(),
+          version,
+          schema::CURRENT_VERSION
+        );
       }
     }
-  };
-  // Everytime bookmark runs, it remove duplicates
-  let new_bookmarks = dedup(&bookmarks[..], &config.bookmarks)?;
-  if new_bookmarks.len() < bookmarks.len() {
-    println!("deduped {} entries", bookmarks.len() - new_bookmarks.len());
-    bookmarks = new_bookmarks;
   }
   let url_store = UrlStore::new(&config)?;
   // The object used to retrieve the content of bookmark
   // We treat the commands here
   match &opt.command {
-    Some(Commands::Add { url }) => add(&config, &url_store, &mut bookmarks, &url)?,
-    Some(Commands::Hash { hash }) => hash2url(&config, &bookmarks, &hash)?,
-    Some(Commands::Check {}) => {
-      if config.store_articles.unwrap_or(false) || config.search.unwrap_or(false) {
-        check_fetch(&config, &url_store, &bookmarks)?;
+    Some(Commands::Add { url, options }) => {
+      let urls: Vec<String> = if url == "-" {
+        std::io::stdin()
+          .lines()
+          .collect::<Result<Vec<_>, _>>()?
+          .into_iter()
+          .map(|line| line.trim().to_string())
+          .filter(|line| !line.is_empty() && !line.starts_with('#'))
+          .collect()
+      } else {
+        vec![url.clone()]
+      };
+      // The bookmark file is written once for all the urls, so that a long
+      // list does not rotate every backup out
+      let mut added = 0;
+      for url in &urls {
+        match add(&config, &url_store, &mut bookmarks, url, options)? {
+          AddOutcome::Added => {
+            history.push(format!("add {}", url));
+            added += 1;
+          }
+          AddOutcome::AlreadyPresent => (),
+          AddOutcome::Failed => failed = true,
+        }
+      }
+      if added > 0 {
+        storage::append_bookmarks(&config, &bookmarks, added)?;
+      }
+    }
+    Some(Commands::Remove { hash }) => {
+      for bookmark in remove(&mut bookmarks, hash) {
+        history.push(format!("remove {} ({})", bookmark.title, bookmark.href));
+      }
+      write_bookmarks(&config, &bookmarks)?;
+    }
+    Some(Commands::Note { hash }) => {
+      let Some(bookmark) = bookmarks.iter_mut().find(|b| b.hash == *hash) else {
+        eprintln!("hash not found {}", hash);
+        std::process::exit(1);
+      };
+      if notes::edit(&url_store, bookmark)? {
+        history.push(format!("note {} ({})", bookmark.title, bookmark.href));
+        if config.search.unwrap_or(false) {
+          if let Err(e) = Embedder::new().and_then(|embedder| url_store.reindex(&embedder, bookmark)) {
+            eprintln!("error: could not index {} ({})", bookmark.href, e);
+          }
+        }
+        write_bookmarks(&config, &bookmarks)?;
+      }
+    }
+    Some(Commands::Refresh { hash }) => {
+      // Like fetch_article, only keep copies of the pages when configured so
+      if !(config.store_articles.unwrap_or(false) || config.search.unwrap_or(false)) {
+        eprintln!("Storing the articles is not enabled. Edit your configuration and set 'store_articles: true'.");
+        std::process::exit(1);
+      }
+      if let Some(hash) = hash.as_ref().filter(|h| !bookmarks.iter().any(|b| b.hash == **h)) {
+        eprintln!("hash not found {}", hash);
+        std::process::exit(1);
+      }
+      let mut changed = 0;
+      let mut embedder = None;
+      for bookmark in bookmarks.iter_mut().filter(|b| hash.as_ref().is_none_or(|h| b.hash == *h)) {
+        if snapshots::refresh(&config, &url_store, &mut embedder, bookmark)? {
+          changed += 1;
+        }
+      }
+      println!("{} pages changed", changed);
+      write_bookmarks(&config, &bookmarks)?;
+      history.push("refresh".to_string());
+    }
+    Some(Commands::Diff { hash, from, to, list }) => {
+      let Some(bookmark) = bookmarks.iter().find(|b| b.hash == *hash) else {
+        eprintln!("hash not found {}", hash);
+        std::process::exit(1);
+      };
+      if *list {
+        for timestamp in snapshots::list(&url_store, &bookmark.href)? {
+          println!("{}", timestamp);
+        }
+      } else {
+        snapshots::diff(&url_store, &bookmark.href, from.as_deref(), to.as_deref())?;
+      }
+    }
+    Some(Commands::Read { hash, unread }) => {
+      let read = if *unread { None } else { Some(chrono::offset::Utc::now().naive_utc()) };
+      let action = if *unread { "mark unread" } else { "mark read" };
+      for bookmark in update(&mut bookmarks, hash, |meta| meta.read = read) {
+        let message = format!("{} {} ({})", action, bookmark.title, bookmark.href);
+        println!("{}", message);
+        history.push(message);
+      }
+      write_bookmarks(&config, &bookmarks)?;
+    }
+    Some(Commands::Star { hash, remove }) => {
+      let action = if *remove { "unstar" } else { "star" };
+      for bookmark in update(&mut bookmarks, hash, |meta| meta.starred = !remove) {
+        let message = format!("{} {} ({})", action, bookmark.title, bookmark.href);
+        println!("{}", message);
+        history.push(message);
+      }
+      write_bookmarks(&config, &bookmarks)?;
+    }
+    Some(Commands::Archive { hash, remove }) => {
+      let action = if *remove { "unarchive" } else { "archive" };
+      for bookmark in update(&mut bookmarks, hash, |meta| meta.archived = !remove) {
+        let message = format!("{} {} ({})", action, bookmark.title, bookmark.href);
+        println!("{}", message);
+        history.push(message);
+      }
+      write_bookmarks(&config, &bookmarks)?;
+    }
+    Some(Commands::Sync {}) => {
+      if !git::enabled(&config) {
+        eprintln!("git is not enabled. Edit your configuration and set 'git: enabled: true'.");
+        std::process::exit(1);
+      }
+      if !history.is_empty() {
+        git::commit(&config, &history.join("\n"))?;
+        history.clear();
+      }
+      git::sync(&config)?;
+    }
+    Some(Commands::Hash { hash }) => hash2url(&config, &bookmarks, hash)?,
+    Some(Commands::Open { bookmarks: targets, offline }) => {
+      for target in targets {
+        let Some(bookmark) = find_bookmark(&bookmarks, target) else {
+          eprintln!("hash not found {}", target);
+          failed = true;
+          continue;
+        };
+        if !offline {
+          open_in_browser(&bookmark.href)?;
+        } else if url_store.has(&bookmark.href) {
+          open_in_browser(&url_store.url_to_path(&bookmark.href).to_string_lossy())?;
+        } else {
+          eprintln!("no stored copy of {}", bookmark.href);
+          failed = true;
+        }
+      }
+    }
+    Some(Commands::Check { links: true, update_redirects }) => {
+      let updated = links::check_all(&config, &url_store, &mut bookmarks, *update_redirects)?;
+      let result = dedup(&config, &bookmarks);
+      if result.bookmarks.len() < bookmarks.len() {
+        println!("merged {} entries", bookmarks.len() - result.bookmarks.len());
+      }
+      write_bookmarks(&config, &result.bookmarks)?;
+      history.push("check: links".to_string());
+      for (from, to) in updated {
+        history.push(format!("update {} -> {}", from, to));
+      }
+    }
+    Some(Commands::Check { links: false, .. }) => {
+      if (config.store_articles.unwrap_or(false) || config.search.unwrap_or(false))
+        && check_fetch(&config, &url_store, &mut bookmarks)?
+      {
+        write_bookmarks(&config, &bookmarks)?;
+        history.push("check: fetch missing articles".to_string());
       }
       if config.search.unwrap_or(false) {
         check_embeddings(&config, &url_store, &bookmarks)?;
       }
     },
-    Some(Commands::Search { needle }) => {
+    Some(Commands::Canonicalize { dry_run }) => {
+      let count = canonicalize_bookmarks(&config, &url_store, &mut bookmarks, *dry_run)?;
+      if *dry_run {
+        println!("{} urls would be canonicalized", count);
+      } else {
+        let result = dedup(&config, &bookmarks);
+        println!(
+          "canonicalized {} urls, merged {} entries",
+          count,
+          bookmarks.len() - result.bookmarks.len()
+        );
+        if count > 0 || result.changed {
+          write_bookmarks(&config, &result.bookmarks)?;
+          history.push(format!("canonicalize {} urls", count));
+        }
+      }
+    }
+    Some(Commands::Migrate { split_data: true, .. }) => split_data_folder(&url_store, &bookmarks)?,
+    Some(Commands::Migrate { shard_data: true, .. }) => shard_data_folder(&url_store, &bookmarks)?,
+    Some(Commands::Migrate { to: Some(to), output, .. }) => {
+      migrate(&config, &url_store, &bookmarks, *to, output.clone())?
+    }
+    Some(Commands::Migrate { to: None, .. }) => {
+      let store = storage::open(&config)?;
+      let (version, bookmarks) = store.load_versioned()?;
+      if version < schema::CURRENT_VERSION {
+        store.save(&bookmarks)?;
+        println!("upgraded {} from version {} to version {}", config.bookmarks.display(), version, schema::CURRENT_VERSION);
+        history.push(format!("migrate to version {}", schema::CURRENT_VERSION));
+      } else {
+        println!("{} is already at version {}", config.bookmarks.display(), version);
+      }
+    }
+    // Handled before the bookmark file is loaded
+    Some(Commands::Merge { .. })
+    | Some(Commands::Serve { .. })
+    | Some(Commands::NativeHost { .. })
+    | Some(Commands::Tui {}) => (),
+    Some(Commands::Dedup { dry_run }) => {
+      let result = dedup(&config, &bookmarks);
+      for (href, duplicates) in &result.merged {
+        println!("{}", href);
+        for duplicate in duplicates {
+          println!("  <- {}", duplicate);
+        }
+      }
+      let count = bookmarks.len() - result.bookmarks.len();
+      if *dry_run {
+        println!("{} entries would be merged", count);
+      } else {
+        println!("merged {} entries", count);
+        if result.changed {
+          write_bookmarks(&config, &result.bookmarks)?;
+          history.push(format!("dedup: merged {} entries", count));
+        }
+      }
+    }
+    Some(Commands::Search { needle, keyword: true, filter }) => {
+      for bookmark in keyword_search(&url_store, &filter.apply(&config, &bookmarks), needle) {
+        println!("{} ({})", bookmark.title, bookmark.href);
+      }
+    }
+    Some(Commands::Search { needle, keyword: false, filter }) => {
       if !config.search.unwrap_or(false) {
         eprintln!("Search feature is not enabled. Edit your configuration and 'search: true'.");
         return Ok(());
       }
-      search(&config, &bookmarks, &needle)?
+      search(&config, &url_store, &filter.apply(&config, &bookmarks), needle)?
     }
-    None => {
-      // By default, just lists the bookmarks
-      for i in 0..bookmarks.len() {
-        println!("{} {} ({})", i + 1, bookmarks[i].title, bookmarks[i].href);
-      }
+    Some(Commands::List { filter, options }) => list(&config, &bookmarks, filter, options)?,
+    Some(Commands::Export { format, filter }) => {
+      export::export(*format, &filter.apply(&config, &bookmarks), &mut std::io::stdout().lock())?
     }
+    Some(Commands::Gc { delete }) => gc::run(&url_store, &bookmarks, *delete)?,
+    Some(Commands::Stats { json }) => stats::print(&stats::collect(&url_store, &bookmarks)?, *json)?,
+    // By default, just lists the bookmarks
+    None => list(&config, &bookmarks, &Filter::default(), &ListOptions::default())?,
+  }
+
+  if git::enabled(&config) && !history.is_empty() {
+    git::commit(&config, &history.join("\n"))?;
+  }
+  if failed {
+    std::process::exit(1);
   }
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  pub fn config() -> Config {
+    serde_yaml::from_str(DEFAULT_CONFIG).unwrap()
+  }
+
+  pub fn bookmark(href: &str, posted: &str) -> Bookmark {
+    serde_json::from_value(serde_json::json!({
+      "href": href,
+      "title": "",
+      "meta": { "posted": format!("{}T00:00:00", posted) },
+    }))
+    .unwrap()
+  }
+
+  #[test]
+  fn dedup_merges_the_duplicates() {
+    let mut first = bookmark("https://example.com/a/?utm_source=feed", "2024-03-01");
+    first.meta.tags = vec!["rust".to_string()];
+    first.meta.referer = Some("https://news.ycombinator.com/item?id=1".to_string());
+    let mut second = bookmark("https://example.com/a", "2024-01-01");
+    second.title = "A".to_string();
+    second.meta.tags = vec!["rust".to_string(), "web".to_string()];
+    second.meta.referer = Some("https://lobste.rs/s/a".to_string());
+    let other = bookmark("https://example.com/b", "2024-02-01");
+    let result = dedup(&config(), &[first, other, second]);
+    assert!(result.changed);
+    assert_eq!(result.bookmarks.len(), 2);
+    assert_eq!(
+      result.merged,
+      vec![("https://example.com/a/?utm_source=feed".to_string(), vec!["https://example.com/a".to_string()])]
+    );
+    // Sorted by date, the merged bookmark has the earliest one
+    let merged = &result.bookmarks[0];
+    assert_eq!(merged.meta.posted, NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(0, 0, 0));
+    assert_eq!(merged.title, "A");
+    assert_eq!(merged.meta.tags, vec!["rust", "web"]);
+    assert_eq!(merged.meta.referer.as_deref(), Some("https://news.ycombinator.com/item?id=1"));
+    assert_eq!(merged.meta.referers, ["https://lobste.rs/s/a"]);
+    assert_eq!(result.bookmarks[1].href, "https://example.com/b");
+  }
+
+  #[test]
+  fn dedup_without_duplicates_changes_nothing() {
+    let bookmarks = [bookmark("https://example.com/a", "2024-01-01"), bookmark("https://example.com/b", "2024-02-01")];
+    let result = dedup(&config(), &bookmarks);
+    assert!(!result.changed);
+    assert!(result.merged.is_empty());
+    assert_eq!(result.bookmarks.len(), 2);
+  }
+
+  #[test]
+  fn dedup_merges_many_duplicates() {
+    let bookmarks: Vec<Bookmark> =
+      (0..2_000).map(|i| bookmark(&format!("https://example.com/{}", i % 1_000), "2024-01-01")).collect();
+    let result = dedup(&config(), &bookmarks);
+    assert_eq!(result.bookmarks.len(), 1_000);
+    assert_eq!(result.merged.len(), 1_000);
+    assert_eq!(result.bookmarks[999].href, "https://example.com/999");
+  }
+
+  #[test]
+  fn parse_until_includes_the_whole_day() {
+    let day = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+    assert_eq!(parse_until("2024-03-01").unwrap(), day.and_hms_nano_opt(23, 59, 59, 999_999_999).unwrap());
+    assert_eq!(parse_until("2024-03-01T12:30:00").unwrap(), day.and_hms_opt(12, 30, 0).unwrap());
+    assert!(parse_until("March 1st").is_err());
+  }
+
+  #[test]
+  fn truncate_fits_the_width() {
+    assert_eq!(truncate("short", 10), "short");
+    assert_eq!(truncate("exactly", 7), "exactly");
+    assert_eq!(truncate("a longer title", 8), "a longe…");
+    // The wide characters take two columns
+    assert_eq!(truncate("日本語のタイトル", 7), "日本語…");
+  }
+
+  #[test]
+  fn filter_by_domain_ignores_www() {
+    let bookmarks = [
+      bookmark("https://www.example.com/a", "2024-01-01"),
+      bookmark("https://blog.example.com/b", "2024-01-01"),
+      bookmark("https://example.org/c", "2024-01-01"),
+    ];
+    for domain in ["example.com", "www.example.com"] {
+      let filter = Filter { domain: Some(domain.to_string()), ..Default::default() };
+      let hrefs: Vec<&str> = filter.apply(&config(), &bookmarks).iter().map(|b| b.href.as_str()).collect();
+      assert_eq!(hrefs, ["https://www.example.com/a", "https://blog.example.com/b"]);
+    }
+  }
+
+  #[test]
+  fn parses_the_templates() {
+    assert_eq!(parse_template("{index}\\t{title}\\n").unwrap(), "{index}\t{title}\n");
+    assert_eq!(parse_template("a \\\\ b").unwrap(), "a \\ b");
+    assert!(parse_template("{title").is_err());
+    assert!(parse_template("{name}").is_err());
+  }
+
+  #[test]
+  fn formats_the_bookmarks() {
+    let mut bookmark = bookmark("https://example.com/a", "2024-03-01");
+    bookmark.hash = "abc".to_string();
+    bookmark.title = "A\ttitle\non two lines".to_string();
+    bookmark.meta.tags = vec!["rust".to_string(), "web".to_string()];
+    assert_eq!(
+      format_bookmark("{index} {hash} {posted} {href} [{tags}] {user}|{title}", 3, &bookmark),
+      "3 abc 2024-03-01 https://example.com/a [rust,web] |A title on two lines"
+    );
+  }
+}
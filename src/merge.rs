@@ -0,0 +1,314 @@
+// Three-way merge of bookmark lists.
+// Bookmarks are matched by hash. A bookmark deleted on one side and untouched
+// on the other is deleted. The fields modified on one side get the
+// modification, the ones changed differently on both sides are conflicts,
+// except the lists (referers, tags and highlights), merged item by item, and the outcomes of the
+// fetches and link checks, where the latest wins.
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+
+use crate::storage;
+use crate::{merge_bookmark, Bookmark, Config};
+
+pub struct Merge {
+  pub bookmarks: Vec<Bookmark>,
+  // Human readable description of the conflicts. The merged bookmarks keep
+  // our version of the conflicting fields.
+  pub conflicts: Vec<String>,
+}
+
+fn same<T: Serialize>(a: &T, b: &T) -> bool {
+  serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+// Merges the items of a list, such as the tags: an item removed on one side is
+// removed, the ones added on either side are kept.
+fn merge_list<T: PartialEq + Clone>(base: &[T], ours: &[T], theirs: &[T]) -> Vec<T> {
+  let mut items: Vec<T> = ours
+    .iter()
+    .filter(|item| theirs.contains(item) || !base.contains(item))
+    .cloned()
+    .collect();
+  for item in theirs {
+    if !base.contains(item) && !items.contains(item) {
+      items.push(item.clone());
+    }
+  }
+  items
+}
+
+// The base, our and their version of a value
+type Versions<'a, T> = (&'a T, &'a T, &'a T);
+
+// Merges a value three ways: the side which changed it from the base wins.
+// None when both sides changed it differently.
+fn three_way<T: Serialize + Clone>(base: &T, ours: &T, theirs: &T) -> Option<T> {
+  if same(ours, theirs) || same(theirs, base) {
+    Some(ours.clone())
+  } else if same(ours, base) {
+    Some(theirs.clone())
+  } else {
+    None
+  }
+}
+
+// Merges a field three ways, keeping ours and reporting the conflict when both
+// sides changed it differently
+fn merge_field<T: Serialize + Clone>(
+  href: &str,
+  name: &str,
+  (base, ours, theirs): Versions<T>,
+  conflicts: &mut Vec<String>,
+) -> T {
+  three_way(base, ours, theirs).unwrap_or_else(|| {
+    conflicts.push(format!("{}: {} changed on both sides, keeping ours", href, name));
+    ours.clone()
+  })
+}
+
+// Merges the fields unknown to this version, one by one
+fn merge_extra(
+  href: &str,
+  (base, ours, theirs): Versions<Map<String, Value>>,
+  conflicts: &mut Vec<String>,
+) -> Map<String, Value> {
+  let keys: BTreeSet<&String> = base.keys().chain(ours.keys()).chain(theirs.keys()).collect();
+  let mut merged = Map::new();
+  for key in keys {
+    let values = (&base.get(key).cloned(), &ours.get(key).cloned(), &theirs.get(key).cloned());
+    if let Some(value) = merge_field(href, key, values, conflicts) {
+      merged.insert(key.clone(), value);
+    }
+  }
+  merged
+}
+
+// The outcome of the latest fetch or check, whichever side made it
+fn latest<T: Clone>(ours: &Option<T>, theirs: &Option<T>, date: impl Fn(&T) -> NaiveDateTime) -> Option<T> {
+  match (ours, theirs) {
+    (Some(o), Some(t)) if date(t) > date(o) => theirs.clone(),
+    (None, _) => theirs.clone(),
+    _ => ours.clone(),
+  }
+}
+
+// Merges a bookmark present on both sides. The base is None when the bookmark
+// was added on both sides, the two are then merged as duplicates.
+fn merge_one(
+  base: Option<&Bookmark>,
+  ours: &Bookmark,
+  theirs: &Bookmark,
+  conflicts: &mut Vec<String>,
+) -> Bookmark {
+  let mut merged = ours.clone();
+  let Some(base) = base else {
+    merge_bookmark(&mut merged, theirs);
+    for (key, value) in &theirs.extra {
+      merged.extra.entry(key.clone()).or_insert(value.clone());
+    }
+    return merged;
+  };
+  let href = &ours.href;
+  let (b, o, t) = (&base.meta, &ours.meta, &theirs.meta);
+  merged.title = merge_field(href, "title", (&base.title, &ours.title, &theirs.title), conflicts);
+  merged.meta.posted = merge_field(href, "posted", (&b.posted, &o.posted, &t.posted), conflicts);
+  merged.meta.user = merge_field(href, "user", (&b.user, &o.user, &t.user), conflicts);
+  merged.meta.referer = merge_field(href, "referer", (&b.referer, &o.referer, &t.referer), conflicts);
+  merged.meta.read = merge_field(href, "read", (&b.read, &o.read, &t.read), conflicts);
+  merged.meta.starred = merge_field(href, "starred", (&b.starred, &o.starred, &t.starred), conflicts);
+  merged.meta.archived = merge_field(href, "archived", (&b.archived, &o.archived, &t.archived), conflicts);
+  merged.meta.notes = merge_field(href, "notes", (&b.notes, &o.notes, &t.notes), conflicts);
+  merged.meta.referers = merge_list(&b.referers, &o.referers, &t.referers);
+  merged.meta.tags = merge_list(&b.tags, &o.tags, &t.tags);
+  merged.meta.highlights = merge_list(&b.highlights, &o.highlights, &t.highlights);
+  // Fetching and checking again on both sides is no conflict, the latest wins
+  merged.meta.fetch =
+    three_way(&b.fetch, &o.fetch, &t.fetch).unwrap_or_else(|| latest(&o.fetch, &t.fetch, |f| f.date));
+  merged.meta.link = three_way(&b.link, &o.link, &t.link).unwrap_or_else(|| latest(&o.link, &t.link, |l| l.date));
+  merged.meta.extra = merge_extra(href, (&b.extra, &o.extra, &t.extra), conflicts);
+  merged.extra = merge_extra(href, (&base.extra, &ours.extra, &theirs.extra), conflicts);
+  merged
+}
+
+// Merges two versions of a bookmark list derived from a common base.
+// The order of ours is kept and the bookmarks added by theirs are appended.
+pub fn merge(base: &[Bookmark], ours: &[Bookmark], theirs: &[Bookmark]) -> Merge {
+  let base: HashMap<&str, &Bookmark> = base.iter().map(|b| (b.hash.as_str(), b)).collect();
+  let theirs_by_hash: HashMap<&str, &Bookmark> =
+    theirs.iter().map(|b| (b.hash.as_str(), b)).collect();
+  let ours_hashes: HashSet<&str> = ours.iter().map(|b| b.hash.as_str()).collect();
+  let mut conflicts = vec![];
+  let mut bookmarks = vec![];
+  for bookmark in ours {
+    let base_bookmark = base.get(bookmark.hash.as_str()).copied();
+    match (theirs_by_hash.get(bookmark.hash.as_str()), base_bookmark) {
+      (Some(their_bookmark), _) => {
+        bookmarks.push(merge_one(base_bookmark, bookmark, their_bookmark, &mut conflicts))
+      }
+      // Added by us
+      (None, None) => bookmarks.push(bookmark.clone()),
+      // Deleted by them
+      (None, Some(base_bookmark)) => {
+        if !same(bookmark, base_bookmark) {
+          eprintln!("warning: {} was modified but deleted on the other side, keeping it", bookmark.href);
+          bookmarks.push(bookmark.clone());
+        }
+      }
+    }
+  }
+  for bookmark in theirs {
+    if ours_hashes.contains(bookmark.hash.as_str()) {
+      continue;
+    }
+    match base.get(bookmark.hash.as_str()) {
+      // Added by them
+      None => bookmarks.push(bookmark.clone()),
+      // Deleted by us
+      Some(base_bookmark) => {
+        if !same(bookmark, *base_bookmark) {
+          eprintln!("warning: {} was modified but deleted on the other side, keeping it", bookmark.href);
+          bookmarks.push(bookmark.clone());
+        }
+      }
+    }
+  }
+  Merge { bookmarks, conflicts }
+}
+
+// Merges three versions of a bookmark file, writing the result to ours. This is
+// the interface of git merge drivers. The files are in the format of the
+// configured bookmark file. Returns the conflicts.
+pub fn merge_files(config: &Config, base: &Path, ours: &Path, theirs: &Path) -> Result<Vec<String>> {
+  let format = storage::format(config);
+  let load = |path: &Path| {
+    storage::open_store(path, format, 0)?
+      .load()
+      .with_context(|| format!("could not load {}", path.display()))
+  };
+  let result = merge(&load(base)?, &load(ours)?, &load(theirs)?);
+  storage::open_store(ours, format, 0)?.save(&result.bookmarks)?;
+  Ok(result.conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::bookmark;
+  use std::slice::from_ref;
+
+  fn titled(href: &str, title: &str) -> Bookmark {
+    let mut bookmark = bookmark(href, "2024-01-01");
+    bookmark.title = title.to_string();
+    bookmark
+  }
+
+  fn hrefs(merge: &Merge) -> Vec<&str> {
+    merge.bookmarks.iter().map(|b| b.href.as_str()).collect()
+  }
+
+  #[test]
+  fn keeps_the_additions_and_deletions_of_both_sides() {
+    let base = [titled("https://a.com/", "A"), titled("https://b.com/", "B"), titled("https://c.com/", "C")];
+    let ours = [titled("https://a.com/", "A"), titled("https://c.com/", "C"), titled("https://d.com/", "D")];
+    let theirs = [titled("https://a.com/", "A"), titled("https://b.com/", "B"), titled("https://e.com/", "E")];
+    let merge = merge(&base, &ours, &theirs);
+    assert_eq!(hrefs(&merge), ["https://a.com/", "https://d.com/", "https://e.com/"]);
+    assert!(merge.conflicts.is_empty());
+  }
+
+  #[test]
+  fn keeps_a_bookmark_modified_on_the_side_which_did_not_delete_it() {
+    let base = [titled("https://a.com/", "A")];
+    let theirs = [titled("https://a.com/", "A2")];
+    let merge = merge(&base, &[], &theirs);
+    assert_eq!(hrefs(&merge), ["https://a.com/"]);
+    assert_eq!(merge.bookmarks[0].title, "A2");
+  }
+
+  #[test]
+  fn merges_the_metadata() {
+    let mut base = titled("https://a.com/", "A");
+    base.meta.tags = vec!["old".to_string(), "kept".to_string()];
+    let mut ours = base.clone();
+    ours.title = "Our A".to_string();
+    ours.meta.tags = vec!["kept".to_string(), "ours".to_string()];
+    let mut theirs = base.clone();
+    theirs.meta.tags = vec!["old".to_string(), "kept".to_string(), "theirs".to_string()];
+    theirs.meta.starred = true;
+    let merge = merge(&[base], &[ours], &[theirs]);
+    let merged = &merge.bookmarks[0];
+    assert_eq!(merged.title, "Our A");
+    assert_eq!(merged.meta.tags, ["kept", "ours", "theirs"]);
+    assert!(merged.meta.starred);
+    assert!(merge.conflicts.is_empty());
+  }
+
+  #[test]
+  fn reports_the_conflicts_and_keeps_ours() {
+    let mut base = titled("https://a.com/", "A");
+    base.meta.notes = Some("notes".to_string());
+    let mut ours = titled("https://a.com/", "Our A");
+    ours.meta.notes = Some("our notes".to_string());
+    let mut theirs = titled("https://a.com/", "Their A");
+    theirs.meta.notes = Some("their notes".to_string());
+    let merge = merge(&[base], &[ours], &[theirs]);
+    let merged = &merge.bookmarks[0];
+    assert_eq!(merged.title, "Our A");
+    assert_eq!(merged.meta.notes.as_deref(), Some("our notes"));
+    assert_eq!(merge.conflicts.len(), 2);
+  }
+
+  #[test]
+  fn keeps_the_flags_cleared_on_one_side() {
+    let mut base = titled("https://a.com/", "A");
+    base.meta.starred = true;
+    base.meta.archived = true;
+    base.meta.read = base.meta.posted;
+    let mut ours = base.clone();
+    ours.meta.starred = false;
+    ours.meta.read = None;
+    let mut theirs = base.clone();
+    theirs.meta.tags = vec!["rust".to_string()];
+    theirs.meta.archived = false;
+    let merge = merge(&[base], &[ours], &[theirs]);
+    let merged = &merge.bookmarks[0];
+    assert!(!merged.meta.starred);
+    assert!(!merged.meta.archived);
+    assert_eq!(merged.meta.read, None);
+    assert_eq!(merged.meta.tags, ["rust"]);
+    assert!(merge.conflicts.is_empty());
+  }
+
+  #[test]
+  fn keeps_the_fields_cleared_on_one_side() {
+    let mut base = titled("https://a.com/", "A");
+    base.meta.notes = Some("notes".to_string());
+    let mut ours = base.clone();
+    ours.title = String::new();
+    ours.meta.notes = None;
+    for (ours, theirs) in [(&ours, &base), (&base, &ours)] {
+      let merge = merge(from_ref(&base), from_ref(ours), from_ref(theirs));
+      assert_eq!(merge.bookmarks[0].title, "");
+      assert_eq!(merge.bookmarks[0].meta.notes, None);
+      assert!(merge.conflicts.is_empty());
+    }
+  }
+
+  #[test]
+  fn keeps_the_latest_fetch() {
+    let base = titled("https://a.com/", "A");
+    let fetched = |date: &str| {
+      let mut bookmark = base.clone();
+      bookmark.meta.fetch = serde_json::from_value(serde_json::json!({ "date": date })).unwrap();
+      bookmark
+    };
+    let merge = merge(from_ref(&base), &[fetched("2024-02-01T00:00:00")], &[fetched("2024-03-01T00:00:00")]);
+    let date = merge.bookmarks[0].meta.fetch.as_ref().map(|f| f.date.to_string());
+    assert_eq!(date.as_deref(), Some("2024-03-01 00:00:00"));
+    assert!(merge.conflicts.is_empty());
+  }
+}
@@ -0,0 +1,108 @@
+// Native messaging host, through which browser extensions add the current page
+// with its rendered DOM (see the native messaging documentation of Chrome and
+// Firefox).
+// The browser starts the host and exchanges JSON messages on its standard input
+// and output, each preceded by its length as a 32 bits integer in native byte
+// order. The messages are the bodies of POST /bookmarks (see server.rs) and the
+// responses the ones of the server, with the status.
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::AsFd;
+
+use crate::server::{add_bookmark, AddRequest};
+use crate::{storage, Config, UrlStore};
+
+// Returns the standard output for the messages. What the commands print goes to
+// the standard error instead, which the browsers log.
+fn take_stdout() -> Result<File> {
+  let stdout = std::io::stdout().as_fd().try_clone_to_owned()?;
+  if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } == -1 {
+    return Err(std::io::Error::last_os_error().into());
+  }
+  Ok(File::from(stdout))
+}
+
+// The largest message read. The browsers send at most 4 GiB but the pages with
+// their DOM are much smaller.
+const MAX_MESSAGE_SIZE: u32 = 8 * 1024 * 1024;
+
+// Returns None once the browser closed the standard input
+fn read_message(input: &mut impl Read) -> Result<Option<Vec<u8>>> {
+  let mut length = [0; 4];
+  match input.read_exact(&mut length) {
+    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+    result => result?,
+  }
+  let length = u32::from_ne_bytes(length);
+  if length > MAX_MESSAGE_SIZE {
+    anyhow::bail!("message of {} bytes, the limit is {}", length, MAX_MESSAGE_SIZE);
+  }
+  let mut message = vec![0; length as usize];
+  input.read_exact(&mut message)?;
+  Ok(Some(message))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> Result<()> {
+  let message = message.to_string();
+  output.write_all(&(message.len() as u32).to_ne_bytes())?;
+  output.write_all(message.as_bytes())?;
+  output.flush()?;
+  Ok(())
+}
+
+fn handle(config: &Config, url_store: &UrlStore, message: &[u8]) -> Result<Value> {
+  let request: AddRequest = match serde_json::from_slice(message) {
+    Ok(request) => request,
+    Err(e) => return Ok(json!({ "status": 400, "error": e.to_string() })),
+  };
+  let _lock = storage::lock(&config.bookmarks)?;
+  let mut bookmarks = storage::load_bookmarks(config)?;
+  let (status, mut body) = add_bookmark(config, url_store, &mut bookmarks, request)?;
+  body["status"] = json!(status);
+  Ok(body)
+}
+
+// Answers the messages until the browser closes the standard input
+pub fn run(config: &Config) -> Result<()> {
+  let mut output = take_stdout()?;
+  let mut input = std::io::stdin().lock();
+  let url_store = UrlStore::new(config)?;
+  while let Some(message) = read_message(&mut input)? {
+    let response = handle(config, &url_store, &message).unwrap_or_else(|e| {
+      eprintln!("error: {:#}", e);
+      json!({ "status": 500, "error": format!("{:#}", e) })
+    });
+    write_message(&mut output, &response)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_back_the_written_messages() {
+    let mut stream = Vec::new();
+    write_message(&mut stream, &json!({ "url": "https://example.com/", "tags": ["é"] })).unwrap();
+    write_message(&mut stream, &json!({})).unwrap();
+    let mut input = stream.as_slice();
+    let first: Value = serde_json::from_slice(&read_message(&mut input).unwrap().unwrap()).unwrap();
+    assert_eq!(first, json!({ "url": "https://example.com/", "tags": ["é"] }));
+    assert_eq!(read_message(&mut input).unwrap().unwrap(), b"{}");
+    assert!(read_message(&mut input).unwrap().is_none());
+  }
+
+  #[test]
+  fn rejects_the_oversized_messages() {
+    let mut stream = (MAX_MESSAGE_SIZE + 1).to_ne_bytes().to_vec();
+    stream.extend_from_slice(b"{}");
+    assert!(read_message(&mut stream.as_slice()).is_err());
+    // A message cut short is an error, not the end of the input
+    let mut stream = 10u32.to_ne_bytes().to_vec();
+    stream.extend_from_slice(b"{}");
+    assert!(read_message(&mut stream.as_slice()).is_err());
+  }
+}
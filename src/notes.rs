@@ -0,0 +1,174 @@
+// Personal notes and highlights attached to bookmarks.
+// Both are edited together as a Markdown document: the notes come first and
+// the highlights are the blockquotes following the `## Highlights` heading.
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::process::Command;
+
+use crate::{Bookmark, UrlStore};
+
+const HIGHLIGHTS_HEADING: &str = "## Highlights";
+
+// A quote of the article
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Highlight {
+  pub text: String,
+  // Position of the quote, in characters, in the stored text of the article
+  // (see UrlStore::get_text). None if the quote could not be found there.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub offset: Option<usize>,
+}
+
+fn collapse_whitespaces(text: &str) -> String {
+  text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Returns the position of the quote in the text, in characters. The quote has
+// its whitespaces collapsed, the ones of the text are ignored too: a quote may
+// span several paragraphs.
+fn locate(text: &str, quote: &str) -> Option<usize> {
+  // The text with whitespaces collapsed, and the position in the text of each
+  // of its characters
+  let mut collapsed = String::new();
+  let mut positions = vec![];
+  for (position, c) in text.chars().enumerate() {
+    if !c.is_whitespace() {
+      collapsed.push(c);
+      positions.push(position);
+    } else if !collapsed.is_empty() && !collapsed.ends_with(' ') {
+      collapsed.push(' ');
+      positions.push(position);
+    }
+  }
+  let start = collapsed.find(quote)?;
+  Some(positions[collapsed[..start].chars().count()])
+}
+
+fn to_markdown(bookmark: &Bookmark) -> String {
+  let mut document = format!(
+    "<!-- Notes on {} ({}), in Markdown.\n     Quote the article in blockquotes below the highlights heading. -->\n\n",
+    bookmark.title, bookmark.href
+  );
+  if let Some(notes) = &bookmark.meta.notes {
+    document.push_str(notes);
+    document.push_str("\n\n");
+  }
+  document.push_str(HIGHLIGHTS_HEADING);
+  document.push('\n');
+  for highlight in &bookmark.meta.highlights {
+    document.push_str(&format!("\n> {}\n", highlight.text));
+  }
+  document
+}
+
+// Parses the document back into notes and quotes
+fn parse(document: &str) -> (Option<String>, Vec<String>) {
+  // Drop the leading comment
+  let document = match document.trim_start().strip_prefix("<!--") {
+    Some(rest) => rest.split_once("-->").map(|(_, rest)| rest).unwrap_or(rest),
+    None => document,
+  };
+  let (notes, highlights) = document
+    .split_once(HIGHLIGHTS_HEADING)
+    .unwrap_or((document, ""));
+  let notes = notes.trim();
+  let mut quotes: Vec<String> = vec![];
+  let mut quote: Vec<&str> = vec![];
+  for line in highlights.lines().chain([""]) {
+    match line.trim_start().strip_prefix('>') {
+      Some(text) => quote.push(text.trim()),
+      None if !quote.is_empty() => {
+        quotes.push(quote.join(" "));
+        quote.clear();
+      }
+      None => (),
+    }
+  }
+  let notes = if notes.is_empty() { None } else { Some(notes.to_string()) };
+  (notes, quotes)
+}
+
+// Opens the notes and highlights of the bookmark in the editor of the user and
+// updates the bookmark. Returns whether they changed.
+pub fn edit(url_store: &UrlStore, bookmark: &mut Bookmark) -> Result<bool> {
+  // A new file only readable by the user, with a random name so that no one
+  // can prepare it in the shared temporary directory
+  let mut file = tempfile::Builder::new().prefix("bookmark-note-").suffix(".md").tempfile()?;
+  file.write_all(to_markdown(bookmark).as_bytes())?;
+  file.flush()?;
+  let path = file.path().to_path_buf();
+  let editor = std::env::var("VISUAL")
+    .or_else(|_| std::env::var("EDITOR"))
+    .unwrap_or("vi".to_string());
+  // Through the shell as the editor may come with arguments (e.g. "code -w")
+  let status = Command::new("sh")
+    .arg("-c")
+    .arg(format!("{} \"$1\"", editor))
+    .arg("sh")
+    .arg(&path)
+    .status()
+    .with_context(|| format!("could not run {}", editor))?;
+  let document = std::fs::read_to_string(&path);
+  drop(file);
+  if !status.success() {
+    anyhow::bail!("{} exited with {}", editor, status);
+  }
+  let (notes, quotes) = parse(&document?);
+  let text = url_store.get_text(&bookmark.href).unwrap_or_default();
+  let highlights: Vec<Highlight> = quotes
+    .into_iter()
+    .map(|quote| {
+      let quote = collapse_whitespaces(&quote);
+      let offset = locate(&text, &quote);
+      if offset.is_none() {
+        eprintln!("warning: quote not found in the stored article: {}", quote);
+      }
+      Highlight { text: quote, offset }
+    })
+    .collect();
+  let changed = notes != bookmark.meta.notes || highlights != bookmark.meta.highlights;
+  bookmark.meta.notes = notes;
+  bookmark.meta.highlights = highlights;
+  Ok(changed)
+}
+
+// Returns the text indexed by the search for a bookmark: its notes and
+// highlights, which are the most relevant, followed by the article.
+pub fn index_text(bookmark: &Bookmark, article: &str) -> String {
+  let mut text = String::new();
+  if let Some(notes) = &bookmark.meta.notes {
+    text.push_str(notes);
+    text.push('\n');
+  }
+  for highlight in &bookmark.meta.highlights {
+    text.push_str(&highlight.text);
+    text.push('\n');
+  }
+  text.push_str(article);
+  text
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn locates_the_quotes_in_the_stored_text() {
+    let text = crate::layout::page_text("<html><body><h1>Title</h1><p>First  paragraph.</p><p>Second one.</p></body></html>");
+    assert_eq!(text, "Title\n\nFirst paragraph.\n\nSecond one.\n\n");
+    assert_eq!(locate(&text, "Title"), Some(0));
+    let offset = locate(&text, "paragraph. Second").unwrap();
+    assert_eq!(text.chars().skip(offset).take(10).collect::<String>(), "paragraph.");
+    assert_eq!(locate(&text, "Third"), None);
+  }
+
+  #[test]
+  fn parses_the_notes_and_the_quotes() {
+    let document = "<!-- comment -->\n\nMy notes\n\n## Highlights\n\n> first\n> quote\n\n> second\n";
+    let (notes, quotes) = parse(document);
+    assert_eq!(notes.as_deref(), Some("My notes"));
+    assert_eq!(quotes, ["first quote", "second"]);
+    assert_eq!(parse("## Highlights\n"), (None, vec![]));
+  }
+}
@@ -0,0 +1,153 @@
+// Versioning of the bookmark file schema.
+// Files are written as `{"version": N, "bookmarks": [...]}`. Files written
+// before the schema was versioned are a bare list of bookmarks: version 1.
+// On load, the bookmarks go through the chain of migrations from the version
+// of the file up to CURRENT_VERSION.
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::Bookmark;
+
+pub const CURRENT_VERSION: u32 = 3;
+
+// MIGRATIONS[i] upgrades the bookmarks from version i + 1 to version i + 2
+const MIGRATIONS: &[fn(&mut Value) -> Result<()>] = &[v1_to_v2, v2_to_v3];
+
+// Version 2 only introduced the envelope, the bookmarks are unchanged.
+fn v1_to_v2(_bookmark: &mut Value) -> Result<()> {
+  Ok(())
+}
+
+// Version 3 moved the referers of the merged duplicates, which version 2 joined
+// with spaces in `referer`, to the `referers` list.
+fn v2_to_v3(bookmark: &mut Value) -> Result<()> {
+  let Some(meta) = bookmark.get_mut("meta").and_then(Value::as_object_mut) else {
+    return Ok(());
+  };
+  let Some(referer) = meta.get("referer").and_then(Value::as_str) else {
+    return Ok(());
+  };
+  let mut referers: Vec<Value> = referer.split_whitespace().map(Value::from).collect();
+  if referers.len() > 1 {
+    meta.insert("referer".to_string(), referers.remove(0));
+    meta.insert("referers".to_string(), Value::Array(referers));
+  }
+  Ok(())
+}
+
+#[derive(Serialize)]
+pub struct Envelope<'a> {
+  pub version: u32,
+  pub bookmarks: &'a [Bookmark],
+}
+
+impl<'a> Envelope<'a> {
+  pub fn new(bookmarks: &'a [Bookmark]) -> Self {
+    Envelope { version: CURRENT_VERSION, bookmarks }
+  }
+}
+
+// Fails if the version is too recent for this version of bookmark. Writing
+// such a file would lose what the newer version added.
+pub fn check_version(version: u32) -> Result<()> {
+  if version > CURRENT_VERSION {
+    anyhow::bail!(
+      "the file uses the schema version {} but this version of bookmark only supports up to {}",
+      version,
+      CURRENT_VERSION
+    );
+  }
+  Ok(())
+}
+
+// Upgrades a single bookmark from the given version to the current one.
+pub fn upgrade(version: u32, mut bookmark: Value) -> Result<Bookmark> {
+  check_version(version)?;
+  for migration in &MIGRATIONS[(version.max(1) - 1) as usize..] {
+    migration(&mut bookmark)?;
+  }
+  Ok(serde_json::from_value(bookmark)?)
+}
+
+// Splits a parsed file into its schema version and its bookmarks.
+pub fn unwrap(document: Value) -> Result<(u32, Vec<Value>)> {
+  match document {
+    Value::Array(bookmarks) => Ok((1, bookmarks)),
+    Value::Object(mut envelope) => {
+      let version = envelope
+        .get("version")
+        .and_then(Value::as_u64)
+        .context("missing schema version")? as u32;
+      match envelope.remove("bookmarks") {
+        Some(Value::Array(bookmarks)) => Ok((version, bookmarks)),
+        None => Ok((version, vec![])),
+        Some(_) => anyhow::bail!("'bookmarks' is not a list"),
+      }
+    }
+    _ => anyhow::bail!("expected a list of bookmarks"),
+  }
+}
+
+// Parses a file already turned into a JSON value, upgrading the bookmarks.
+pub fn load(document: Value) -> Result<(u32, Vec<Bookmark>)> {
+  let (version, bookmarks) = unwrap(document)?;
+  check_version(version)?;
+  let bookmarks = bookmarks
+    .into_iter()
+    .enumerate()
+    .map(|(i, bookmark)| upgrade(version, bookmark).with_context(|| format!("bookmark {}", i + 1)))
+    .collect::<Result<_>>()?;
+  Ok((version, bookmarks))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn bookmark() -> Value {
+    json!({ "href": "https://example.com/", "title": "Example", "meta": { "tags": ["a"] } })
+  }
+
+  #[test]
+  fn upgrades_every_version() {
+    for version in 1..=CURRENT_VERSION {
+      let bookmark = upgrade(version, bookmark()).unwrap();
+      assert_eq!(bookmark.href, "https://example.com/");
+      assert_eq!(bookmark.meta.tags, vec!["a"]);
+    }
+  }
+
+  #[test]
+  fn splits_the_referers_of_version_2() {
+    let mut bookmark = bookmark();
+    bookmark["meta"]["referer"] = json!("https://a.com/ https://b.com/ https://c.com/");
+    let bookmark = upgrade(2, bookmark).unwrap();
+    assert_eq!(bookmark.meta.referer.as_deref(), Some("https://a.com/"));
+    assert_eq!(bookmark.meta.referers, ["https://b.com/", "https://c.com/"]);
+    let mut single = self::bookmark();
+    single["meta"]["referer"] = json!("https://a.com/");
+    let single = upgrade(2, single).unwrap();
+    assert_eq!(single.meta.referer.as_deref(), Some("https://a.com/"));
+    assert!(single.meta.referers.is_empty());
+  }
+
+  #[test]
+  fn refuses_newer_versions() {
+    assert!(upgrade(CURRENT_VERSION + 1, bookmark()).is_err());
+    assert!(load(json!({ "version": CURRENT_VERSION + 1, "bookmarks": [] })).is_err());
+  }
+
+  #[test]
+  fn unwraps_the_envelope() {
+    let (version, bookmarks) = unwrap(json!([bookmark()])).unwrap();
+    assert_eq!((version, bookmarks.len()), (1, 1));
+    let (version, bookmarks) = unwrap(json!({ "version": 2, "bookmarks": [bookmark()] })).unwrap();
+    assert_eq!((version, bookmarks.len()), (2, 1));
+    let (version, bookmarks) = unwrap(json!({ "version": 2 })).unwrap();
+    assert_eq!((version, bookmarks.len()), (2, 0));
+    assert!(unwrap(json!({ "bookmarks": [] })).is_err());
+    assert!(unwrap(json!({ "version": 2, "bookmarks": {} })).is_err());
+  }
+}
@@ -0,0 +1,513 @@
+// A local HTTP server exposing the bookmarks through a JSON API and a web
+// interface (see web.rs).
+// The bookmark file is locked and loaded for each request so that the command
+// line can still be used while the server runs. The embedding model and the
+// embeddings are kept in memory between requests.
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::SystemTime;
+use tiny_http::{Header, Method, Request};
+
+use crate::web;
+use crate::{
+  add, canonicalize, git, keyword_search, parse_date, parse_until, remove, semantic_search, stats, storage,
+  AddOptions, AddOutcome, Bookmark, Config, Embedder, Filter, UrlStore,
+};
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+pub type Response = tiny_http::Response<std::io::Cursor<Vec<u8>>>;
+
+// The body of POST /bookmarks, also the messages of the native messaging host
+#[derive(Deserialize, Default)]
+pub struct AddRequest {
+  pub url: String,
+  pub title: Option<String>,
+  #[serde(default)]
+  pub tags: Vec<String>,
+  pub note: Option<String>,
+  pub posted: Option<String>,
+  #[serde(default)]
+  pub no_fetch: bool,
+  #[serde(default)]
+  pub strict: bool,
+  // The page as rendered by the browser, stored instead of fetching it
+  pub html: Option<String>,
+}
+
+fn json_response(status: u16, value: &Value) -> Response {
+  tiny_http::Response::from_string(value.to_string())
+    .with_status_code(status)
+    .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn error(status: u16, message: &str) -> Response {
+  json_response(status, &json!({ "error": message }))
+}
+
+// The JSON representation of a bookmark, with its hash
+fn bookmark_json(bookmark: &Bookmark) -> Value {
+  let mut value = serde_json::to_value(bookmark).unwrap_or_default();
+  value["hash"] = json!(bookmark.hash);
+  value
+}
+
+// Returns the bookmark of the url, compared in their canonical form
+fn find<'b>(config: &Config, bookmarks: &'b [Bookmark], url: &str) -> Option<&'b Bookmark> {
+  let url = canonicalize(config, url);
+  bookmarks.iter().find(|b| canonicalize(config, &b.href) == url)
+}
+
+fn commit(config: &Config, message: &str) -> Result<()> {
+  if git::enabled(config) {
+    git::commit(config, message)?;
+  }
+  Ok(())
+}
+
+// Adds the bookmark of the request and commits it. Returns the status and the
+// body of the response: the new bookmark, the existing one or the error.
+pub fn add_bookmark(
+  config: &Config,
+  url_store: &UrlStore,
+  bookmarks: &mut Vec<Bookmark>,
+  request: AddRequest,
+) -> Result<(u16, Value)> {
+  let posted = match request.posted.as_deref().map(parse_date).transpose() {
+    Ok(posted) => posted,
+    Err(e) => return Ok((400, json!({ "error": format!("{:#}", e) }))),
+  };
+  let options = AddOptions {
+    title: request.title,
+    no_fetch: request.no_fetch,
+    note: request.note,
+    posted,
+    tags: request.tags,
+    strict: request.strict,
+    html: request.html,
+  };
+  Ok(match add(config, url_store, bookmarks, &request.url, &options)? {
+    AddOutcome::Added => {
+      storage::append_bookmarks(config, bookmarks, 1)?;
+      commit(config, &format!("add {}", request.url))?;
+      (201, bookmarks.last().map(bookmark_json).unwrap_or_default())
+    }
+    AddOutcome::AlreadyPresent => {
+      let existing = find(config, bookmarks, &request.url);
+      (409, json!({ "error": "already present", "bookmark": existing.map(bookmark_json) }))
+    }
+    AddOutcome::Failed => (502, json!({ "error": "could not fetch the page" })),
+  })
+}
+
+// Compares the tokens in constant time so that they cannot be guessed from
+// the response times
+fn same_token(a: &str, b: &str) -> bool {
+  a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Whether the request carries the token. The API clients send it in the
+// Authorization header, the browsers in the cookie set by /login.
+fn has_token(request: &Request, token: &str) -> bool {
+  request.headers().iter().any(|header| {
+    let value = header.value.as_str();
+    if header.field.equiv("Authorization") {
+      value.strip_prefix("Bearer ").is_some_and(|value| same_token(value, token))
+    } else if header.field.equiv("Cookie") {
+      value
+        .split(';')
+        .filter_map(|cookie| cookie.trim().strip_prefix(web::TOKEN_COOKIE))
+        .filter_map(|cookie| cookie.strip_prefix('='))
+        .any(|value| same_token(value, token))
+    } else {
+      false
+    }
+  })
+}
+
+// Whether the request carries the token and was made by the user, typing the
+// address or opening a bookmark of the browser. The pages of other sites
+// cannot send Sec-Fetch-Site: none.
+fn typed_with_token(request: &Request, token: Option<&str>) -> bool {
+  let fetch_site = request.headers().iter().find(|h| h.field.equiv("Sec-Fetch-Site")).map(|h| h.value.as_str());
+  fetch_site == Some("none") && token.is_some_and(|token| has_token(request, token))
+}
+
+// Parses the form posted in the body of the request
+fn read_form(request: &mut Request) -> Result<HashMap<String, String>> {
+  let mut body = String::new();
+  if *request.method() == Method::Post {
+    request.as_reader().read_to_string(&mut body)?;
+  }
+  Ok(Url::parse(&format!("http://localhost/?{}", body))?.query_pairs().into_owned().collect())
+}
+
+// Whether the request was sent by a page of another site, which could otherwise
+// use the cookie, or the server when it has no token. Browsers give the origin
+// of the page or of the extension sending a form or a script request, the other
+// clients give none.
+fn cross_origin(request: &Request) -> bool {
+  if matches!(request.method(), Method::Get | Method::Head) {
+    return false;
+  }
+  let header = |name: &'static str| {
+    request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str())
+  };
+  const EXTENSIONS: [&str; 3] = ["chrome-extension://", "moz-extension://", "safari-web-extension://"];
+  match (header("Origin"), header("Host")) {
+    (None, _) => false,
+    (Some(origin), _) if EXTENSIONS.iter().any(|scheme| origin.starts_with(scheme)) => false,
+    (Some(origin), Some(host)) => origin != format!("http://{}", host),
+    (Some(_), None) => true,
+  }
+}
+
+// The name of the host of the listen address, e.g. 192.168.1.2 for
+// 192.168.1.2:8080 or ::1 for [::1]:8080
+fn host_name(address: &str) -> &str {
+  let host = match address.rsplit_once(':') {
+    Some((host, port)) if !port.contains(']') => host,
+    _ => address,
+  };
+  host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// Whether the Host header names the server. A page of another site whose name
+// was made to resolve to this address (DNS rebinding) sends its own name.
+fn allowed_host(request: &Request, hosts: &[String]) -> bool {
+  let host = request.headers().iter().find(|h| h.field.equiv("Host")).map(|h| h.value.as_str());
+  host.is_some_and(|host| hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host_name(host))))
+}
+
+// Builds the filter from the query parameters named after the options of
+// `bookmark list`. Fails on the invalid dates.
+fn filter(query: &HashMap<String, String>) -> Result<Filter> {
+  let flag = |name: &str| query.get(name).is_some_and(|value| value != "false");
+  let date = |name: &str, parse: fn(&str) -> Result<NaiveDateTime>| {
+    query.get(name).map(|date| parse(date).with_context(|| format!("invalid {} date {}", name, date))).transpose()
+  };
+  Ok(Filter {
+    user: query.get("user").cloned(),
+    mine: flag("mine"),
+    unread: flag("unread"),
+    starred: flag("starred"),
+    archived: flag("archived"),
+    all: flag("all"),
+    since: date("since", parse_date)?,
+    until: date("until", parse_until)?,
+    domain: query.get("domain").cloned(),
+    referer: query.get("referer").cloned(),
+  })
+}
+
+struct Server<'a> {
+  config: &'a Config,
+  url_store: UrlStore<'a>,
+  token: Option<String>,
+  // Loaded on the first semantic search
+  embedder: Option<Embedder>,
+  // The embeddings loaded so far, dropped when the bookmark file changes
+  embeddings: RefCell<HashMap<String, Vec<f32>>>,
+  bookmarks_modified: Option<SystemTime>,
+  // The names the server may be reached with, see allowed_host
+  hosts: Vec<String>,
+}
+
+impl Server<'_> {
+  fn authorized(&self, request: &Request) -> bool {
+    self.token.as_ref().is_none_or(|token| has_token(request, token))
+  }
+
+  // Adds the page of the /add form, or shows the form again with the error
+  fn add_from_form(&self, bookmarks: &mut Vec<Bookmark>, form: &HashMap<String, String>) -> Result<Response> {
+    let field = |name: &str| form.get(name).map(|v| v.trim()).filter(|v| !v.is_empty());
+    let Some(url) = field("url") else {
+      return Ok(web::add_form(form, Some("missing url")));
+    };
+    let add_request = AddRequest {
+      url: url.to_string(),
+      title: field("title").map(str::to_string),
+      tags: field("tags")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect(),
+      note: field("note").map(str::to_string),
+      ..Default::default()
+    };
+    Ok(match add_bookmark(self.config, &self.url_store, bookmarks, add_request)? {
+      (201, _) => web::added(bookmarks.last().unwrap(), false),
+      (409, _) => match find(self.config, bookmarks, url) {
+        Some(bookmark) => web::added(bookmark, true),
+        None => web::add_form(form, Some("already present")),
+      },
+      (_, body) => web::add_form(form, body["error"].as_str()),
+    })
+  }
+
+  fn load(&mut self) -> Result<Vec<Bookmark>> {
+    let modified = std::fs::metadata(&self.config.bookmarks).and_then(|m| m.modified()).ok();
+    if modified != self.bookmarks_modified {
+      self.embeddings.borrow_mut().clear();
+      self.bookmarks_modified = modified;
+    }
+    storage::load_bookmarks(self.config)
+  }
+
+  // Searches the candidates with the semantic search or, with keyword, the
+  // keyword search. The keyword search has no scores.
+  fn search<'b>(
+    &mut self,
+    candidates: &[&'b Bookmark],
+    needle: &str,
+    keyword: bool,
+  ) -> Result<Vec<(Option<f32>, &'b Bookmark)>> {
+    if keyword {
+      let words = [needle.to_string()];
+      return Ok(keyword_search(&self.url_store, candidates, &words).into_iter().map(|b| (None, b)).collect());
+    }
+    if !self.config.search.unwrap_or(false) {
+      anyhow::bail!("the search feature is not enabled");
+    }
+    if self.embedder.is_none() {
+      self.embedder = Some(Embedder::new().map_err(|e| anyhow::anyhow!(e))?);
+    }
+    let embedder = self.embedder.as_ref().unwrap();
+    let similarities = semantic_search(embedder, candidates, needle, |bookmark| {
+      if let Some(embeddings) = self.embeddings.borrow().get(&bookmark.hash) {
+        return Ok(Some(embeddings.clone()));
+      }
+      let embeddings = self.url_store.load_embeddings(&bookmark.href)?;
+      if let Some(embeddings) = &embeddings {
+        self.embeddings.borrow_mut().insert(bookmark.hash.clone(), embeddings.clone());
+      }
+      Ok(embeddings)
+    })
+    .map_err(|e| anyhow::anyhow!(e))?;
+    Ok(similarities.into_iter().map(|(score, bookmark)| (Some(score), bookmark)).collect())
+  }
+
+  fn handle(&mut self, request: &mut Request) -> Result<Response> {
+    let url = Url::parse(&format!("http://localhost{}", request.url()))?;
+    let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let path: Vec<&str> = url.path_segments().map(|s| s.filter(|s| !s.is_empty()).collect()).unwrap_or_default();
+    if !allowed_host(request, &self.hosts) {
+      return Ok(error(403, "unknown host"));
+    }
+    if cross_origin(request) {
+      return Ok(error(403, "cross-origin request"));
+    }
+    if path == ["login"] {
+      // The form is posted so that the token does not end up in the history
+      let form = read_form(request)?;
+      let next = form.get("next").or(query.get("next")).map(String::as_str).unwrap_or("/");
+      return Ok(match (&self.token, form.get("token")) {
+        (Some(token), Some(given)) if same_token(given, token) => web::logged_in(token, next),
+        (_, given) => web::login(given.is_some(), next),
+      });
+    }
+    if !self.authorized(request) {
+      return Ok(match path.first() {
+        Some(&"bookmarks") | Some(&"search") | Some(&"stats") => {
+          error(401, "missing or invalid bearer token")
+        }
+        // Back to the page after logging in, e.g. to the bookmarklet form
+        _ if *request.method() == Method::Get => {
+          let mut login = Url::parse("http://localhost/login")?;
+          login.query_pairs_mut().append_pair("next", request.url());
+          web::redirect(&format!("/login?{}", login.query().unwrap_or_default()))
+        }
+        _ => web::redirect("/login"),
+      });
+    }
+    let _lock = storage::lock(&self.config.bookmarks)?;
+    let mut bookmarks = self.load()?;
+    match (request.method(), path.as_slice()) {
+      (Method::Get, ["bookmarks"]) => {
+        let filter = match filter(&query) {
+          Ok(filter) => filter,
+          Err(e) => return Ok(error(400, &format!("{:#}", e))),
+        };
+        let list: Vec<Value> = filter.apply(self.config, &bookmarks).into_iter().map(bookmark_json).collect();
+        Ok(json_response(200, &json!(list)))
+      }
+      (Method::Get, ["bookmarks", hash]) => match bookmarks.iter().find(|b| b.hash == *hash) {
+        Some(bookmark) => Ok(json_response(200, &bookmark_json(bookmark))),
+        None => Ok(error(404, "hash not found")),
+      },
+      (Method::Post, ["bookmarks"]) => {
+        let mut body = String::new();
+        request.as_reader().read_to_string(&mut body)?;
+        let add_request: AddRequest = match serde_json::from_str(&body) {
+          Ok(add_request) => add_request,
+          Err(e) => return Ok(error(400, &e.to_string())),
+        };
+        let (status, body) = add_bookmark(self.config, &self.url_store, &mut bookmarks, add_request)?;
+        Ok(json_response(status, &body))
+      }
+      (Method::Delete, ["bookmarks", hash]) => {
+        let removed = remove(&mut bookmarks, &[hash.to_string()]);
+        let Some(bookmark) = removed.first() else {
+          return Ok(error(404, "hash not found"));
+        };
+        storage::write_bookmarks(self.config, &bookmarks)?;
+        commit(self.config, &format!("remove {} ({})", bookmark.title, bookmark.href))?;
+        Ok(json_response(200, &bookmark_json(bookmark)))
+      }
+      (Method::Get, ["search"]) => {
+        let Some(needle) = query.get("q").filter(|q| !q.trim().is_empty()) else {
+          return Ok(error(400, "missing q parameter"));
+        };
+        let limit: usize = query.get("limit").and_then(|l| l.parse().ok()).unwrap_or(10);
+        let keyword = query.get("keyword").is_some_and(|k| k != "false");
+        if !keyword && !self.config.search.unwrap_or(false) {
+          return Ok(error(400, "the search feature is not enabled"));
+        }
+        let candidates = match filter(&query) {
+          Ok(filter) => filter.apply(self.config, &bookmarks),
+          Err(e) => return Ok(error(400, &format!("{:#}", e))),
+        };
+        let results: Vec<Value> = self
+          .search(&candidates, needle, keyword)?
+          .into_iter()
+          .take(limit)
+          .map(|(score, bookmark)| match score {
+            Some(score) => json!({ "score": score, "bookmark": bookmark_json(bookmark) }),
+            None => bookmark_json(bookmark),
+          })
+          .collect();
+        Ok(json_response(200, &json!(results)))
+      }
+      (Method::Get, ["stats"]) => {
+        let stats = stats::collect(&self.url_store, &bookmarks)?;
+        Ok(json_response(200, &serde_json::to_value(stats)?))
+      }
+      (Method::Get, []) => {
+        let candidates = match filter(&query) {
+          Ok(filter) => filter.apply(self.config, &bookmarks),
+          Err(e) => return Ok(error(400, &format!("{:#}", e))),
+        };
+        match query.get("q").filter(|q| !q.trim().is_empty()) {
+          Some(needle) => {
+            let keyword = query.contains_key("keyword");
+            match self.search(&candidates, needle, keyword) {
+              Ok(results) => Ok(web::results(&query, &results)),
+              Err(e) => Ok(web::results_error(&query, &format!("{:#}", e))),
+            }
+          }
+          None => Ok(web::listing(&query, &candidates)),
+        }
+      }
+      // The bookmarklet opens the form, which posts the bookmark to add. Adding on
+      // any GET would let other sites add bookmarks with a link, so only the
+      // requests typed by the user with the token add the page directly.
+      (Method::Get, ["add"]) if query.contains_key("url") && typed_with_token(request, self.token.as_deref()) => {
+        self.add_from_form(&mut bookmarks, &query)
+      }
+      (Method::Get, ["add"]) => Ok(web::add_form(&query, None)),
+      (Method::Post, ["add"]) => {
+        let form = read_form(request)?;
+        self.add_from_form(&mut bookmarks, &form)
+      }
+      (Method::Get, ["read", hash]) => match bookmarks.iter().find(|b| b.hash == *hash) {
+        Some(bookmark) => Ok(web::reader(&self.url_store, bookmark, query.contains_key("text"))),
+        None => Ok(web::not_found()),
+      },
+      _ => Ok(error(404, "not found")),
+    }
+  }
+}
+
+// Serves the API and the web interface until the process is killed. The token,
+// if any, is required as a bearer token in the Authorization header of every
+// API request, the web interface asks for it once and keeps it in a cookie.
+pub fn serve(config: &Config, listen: &str, token: Option<String>) -> Result<()> {
+  let server = tiny_http::Server::http(listen)
+    .map_err(|e| anyhow::anyhow!(e))
+    .with_context(|| format!("could not listen on {}", listen))?;
+  println!("listening on http://{}", listen);
+  let mut state = Server {
+    config,
+    url_store: UrlStore::new(config)?,
+    token,
+    embedder: None,
+    embeddings: RefCell::new(HashMap::new()),
+    bookmarks_modified: None,
+    hosts: vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string(), host_name(listen).to_string()],
+  };
+  for mut request in server.incoming_requests() {
+    let response = state.handle(&mut request).unwrap_or_else(|e| {
+      eprintln!("error: {} {}: {:#}", request.method(), request.url(), e);
+      error(500, &format!("{:#}", e))
+    });
+    let _ = request.respond(response);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tiny_http::TestRequest;
+
+  fn request(method: Method, headers: &[(&str, &str)]) -> Request {
+    headers
+      .iter()
+      .fold(TestRequest::new().with_method(method), |request, (field, value)| {
+        request.with_header(Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap())
+      })
+      .into()
+  }
+
+  #[test]
+  fn checks_the_token_and_the_cookie() {
+    assert!(has_token(&request(Method::Get, &[("Authorization", "Bearer s3cret")]), "s3cret"));
+    assert!(has_token(&request(Method::Get, &[("Cookie", "theme=dark; bookmark_token=s3cret")]), "s3cret"));
+    assert!(!has_token(&request(Method::Get, &[]), "s3cret"));
+    assert!(!has_token(&request(Method::Get, &[("Authorization", "Bearer s3cre")]), "s3cret"));
+    assert!(!has_token(&request(Method::Get, &[("Authorization", "Basic s3cret")]), "s3cret"));
+    assert!(!has_token(&request(Method::Get, &[("Cookie", "bookmark_token=other")]), "s3cret"));
+    assert!(!has_token(&request(Method::Get, &[("Cookie", "bookmark_tokenx=s3cret")]), "s3cret"));
+  }
+
+  #[test]
+  fn rejects_the_other_hosts() {
+    let hosts = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    assert!(allowed_host(&request(Method::Get, &[("Host", "localhost:8080")]), &hosts));
+    assert!(allowed_host(&request(Method::Get, &[("Host", "LOCALHOST")]), &hosts));
+    assert!(allowed_host(&request(Method::Get, &[("Host", "[::1]:8080")]), &hosts));
+    assert!(!allowed_host(&request(Method::Get, &[("Host", "evil.example:8080")]), &hosts));
+    assert!(!allowed_host(&request(Method::Get, &[("Host", "localhost.evil.example")]), &hosts));
+    assert!(!allowed_host(&request(Method::Get, &[]), &hosts));
+  }
+
+  #[test]
+  fn rejects_the_cross_origin_posts() {
+    let post = |headers: &[(&str, &str)]| cross_origin(&request(Method::Post, headers));
+    assert!(post(&[("Host", "localhost:8080"), ("Origin", "http://evil.example")]));
+    assert!(post(&[("Origin", "http://localhost:8080")]));
+    assert!(!post(&[("Host", "localhost:8080"), ("Origin", "http://localhost:8080")]));
+    assert!(!post(&[("Host", "localhost:8080"), ("Origin", "moz-extension://1234")]));
+    assert!(!post(&[("Host", "localhost:8080")]));
+    assert!(!cross_origin(&request(Method::Get, &[("Host", "localhost:8080"), ("Origin", "http://evil.example")])));
+  }
+
+  #[test]
+  fn adds_on_get_only_the_requests_typed_with_the_token() {
+    let typed = |headers: &[(&str, &str)], token| typed_with_token(&request(Method::Get, headers), token);
+    let cookie = ("Cookie", "bookmark_token=s3cret");
+    assert!(typed(&[cookie, ("Sec-Fetch-Site", "none")], Some("s3cret")));
+    assert!(typed(&[("Authorization", "Bearer s3cret"), ("Sec-Fetch-Site", "none")], Some("s3cret")));
+    assert!(!typed(&[cookie, ("Sec-Fetch-Site", "cross-site")], Some("s3cret")));
+    assert!(!typed(&[cookie], Some("s3cret")));
+    assert!(!typed(&[("Sec-Fetch-Site", "none")], Some("s3cret")));
+    assert!(!typed(&[cookie, ("Sec-Fetch-Site", "none")], None));
+  }
+}
@@ -0,0 +1,207 @@
+// Dated copies of the articles, to follow how the pages change.
+// The stored article (page.html) is always the most recent copy. Once a page
+// changed, every copy is also kept in snapshots/<timestamp>.html, next to it in
+// the directory of the bookmark (see layout.rs).
+use anyhow::Result;
+use scraper::{Html, Selector};
+use similar::TextDiff;
+use std::path::{Path, PathBuf};
+
+use crate::{fetch_http, layout, Bookmark, Config, Embedder, FetchStatus, UrlStore};
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
+// Below this similarity ratio between the old and the new text, the change is
+// worth recomputing the embeddings
+const REINDEX_RATIO: f32 = 0.9;
+
+// Returns the text of the page, one line per text node, so that it can be
+// diffed line by line
+pub fn text_lines(html: &str) -> String {
+  let document = Html::parse_document(html);
+  let selector = Selector::parse("body").unwrap();
+  let Some(body) = document.select(&selector).next() else {
+    return String::new();
+  };
+  body
+    .text()
+    .map(|text| text.split_whitespace().collect::<Vec<_>>().join(" "))
+    .filter(|line| !line.is_empty())
+    .map(|line| line + "\n")
+    .collect()
+}
+
+fn snapshot_path(url_store: &UrlStore, url: &str, timestamp: &str) -> PathBuf {
+  url_store.url_to_snapshots_path(url).join(format!("{}.html", timestamp))
+}
+
+fn save_snapshot(url_store: &UrlStore, url: &str, timestamp: &str, content: &str) -> Result<()> {
+  std::fs::create_dir_all(url_store.url_to_snapshots_path(url))?;
+  std::fs::write(snapshot_path(url_store, url, timestamp), content)?;
+  layout::record(&url_store.url_to_dir(url), url, &[layout::SNAPSHOTS])
+}
+
+fn modified(path: &Path) -> Result<String> {
+  let modified: chrono::DateTime<chrono::Utc> = std::fs::metadata(path)?.modified()?.into();
+  Ok(modified.format(TIMESTAMP_FORMAT).to_string())
+}
+
+// Returns the timestamps of the copies of the page, oldest first
+pub fn list(url_store: &UrlStore, url: &str) -> Result<Vec<String>> {
+  let Ok(entries) = std::fs::read_dir(url_store.url_to_snapshots_path(url)) else {
+    return Ok(vec![]);
+  };
+  let mut timestamps = vec![];
+  for entry in entries {
+    let path = entry?.path();
+    if path.extension().is_some_and(|e| e == "html") {
+      if let Some(stem) = path.file_stem() {
+        timestamps.push(stem.to_string_lossy().to_string());
+      }
+    }
+  }
+  timestamps.sort();
+  Ok(timestamps)
+}
+
+// How similar the texts of two copies of a page are, 1.0 when they are the same
+fn similarity(previous: &str, content: &str) -> f32 {
+  TextDiff::from_lines(&text_lines(previous), &text_lines(content)).ratio()
+}
+
+// Whether the text changed enough for the embeddings to be recomputed
+fn worth_reindexing(ratio: f32) -> bool {
+  ratio < REINDEX_RATIO
+}
+
+// Fetches the page of the bookmark again and compares its text with the stored
+// copy. When it changed, the new copy replaces the stored one, both are kept
+// as snapshots and the embeddings are recomputed if the change is large
+// enough. The embedder is loaded by the first page to reindex and kept for the
+// next ones. Returns whether the page changed.
+pub fn refresh(
+  config: &Config,
+  url_store: &UrlStore,
+  embedder: &mut Option<Embedder>,
+  bookmark: &mut Bookmark,
+) -> Result<bool> {
+  let url = bookmark.href.clone();
+  let result = fetch_http(config, &url);
+  bookmark.meta.fetch = Some(FetchStatus::new(&result));
+  let content = match result {
+    Ok(content) => content,
+    Err(e) => {
+      eprintln!("error: could not fetch {} ({})", url, e);
+      return Ok(false);
+    }
+  };
+  let now = chrono::offset::Utc::now().format(TIMESTAMP_FORMAT).to_string();
+  let path = url_store.url_to_path(&url);
+  let Ok(previous) = url_store.get_article(&url) else {
+    url_store.write_article(&url, &content)?;
+    println!("saved {}", url);
+    return Ok(false);
+  };
+  let ratio = similarity(&previous, &content);
+  if ratio == 1.0 {
+    return Ok(false);
+  }
+  // The first change, the stored copy becomes the first snapshot
+  if list(url_store, &url)?.is_empty() {
+    save_snapshot(url_store, &url, &modified(&path)?, &previous)?;
+  }
+  save_snapshot(url_store, &url, &now, &content)?;
+  url_store.write_article(&url, &content)?;
+  println!("changed {} ({:.0}% similar)", url, ratio * 100.0);
+  if worth_reindexing(ratio) && config.search.unwrap_or(false) {
+    if embedder.is_none() {
+      *embedder = Some(Embedder::new().map_err(|e| anyhow::anyhow!(e))?);
+    }
+    if let Err(e) = url_store.reindex(embedder.as_ref().unwrap(), bookmark) {
+      eprintln!("error: could not index {} ({})", url, e);
+    }
+  }
+  Ok(true)
+}
+
+// Returns the timestamps of the copies to compare: the given ones or, by
+// default, the two most recent ones
+fn pick(timestamps: &[String], from: Option<&str>, to: Option<&str>, url: &str) -> Result<(String, String)> {
+  let to = match to {
+    Some(to) => to.to_string(),
+    None => match timestamps.last() {
+      Some(last) => last.clone(),
+      None => anyhow::bail!("no copies of {}, run `bookmark refresh` first", url),
+    },
+  };
+  let from = match from {
+    Some(from) => from.to_string(),
+    None => match timestamps.iter().rev().find(|t| **t < to) {
+      Some(previous) => previous.clone(),
+      None => anyhow::bail!("no copy of {} before {}", url, to),
+    },
+  };
+  Ok((from, to))
+}
+
+// Prints the differences between the text of two copies of the page as a
+// unified diff. By default, between the two most recent ones.
+pub fn diff(url_store: &UrlStore, url: &str, from: Option<&str>, to: Option<&str>) -> Result<()> {
+  let (from, to) = pick(&list(url_store, url)?, from, to, url)?;
+  let read = |timestamp: &str| -> Result<String> {
+    let path = snapshot_path(url_store, url, timestamp);
+    match std::fs::read_to_string(&path) {
+      Ok(content) => Ok(text_lines(&content)),
+      Err(_) => anyhow::bail!("no copy of {} at {}, see `bookmark diff --list`", url, timestamp),
+    }
+  };
+  let (old, new) = (read(&from)?, read(&to)?);
+  print!("{}", TextDiff::from_lines(&old, &new).unified_diff().header(&from, &to));
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn page(paragraphs: &[&str]) -> String {
+    let body: String = paragraphs.iter().map(|p| format!("<p>{}</p>", p)).collect();
+    format!("<html><head><title>T</title></head><body>{}</body></html>", body)
+  }
+
+  #[test]
+  fn extracts_one_line_per_text_node() {
+    let html = "<html><head><title>Title</title></head><body><h1>A  heading</h1>\n<p>Some <b>bold</b>\ntext</p> </body></html>";
+    assert_eq!(text_lines(html), "A heading\nSome\nbold\ntext\n");
+    assert_eq!(text_lines("<html><body></body></html>"), "");
+  }
+
+  #[test]
+  fn reindexes_the_large_changes_only() {
+    let paragraphs: Vec<String> = (0..20).map(|i| format!("Paragraph {}", i)).collect();
+    let paragraphs: Vec<&str> = paragraphs.iter().map(String::as_str).collect();
+    let original = page(&paragraphs);
+    assert_eq!(similarity(&original, &original), 1.0);
+    // A single paragraph changed
+    let mut edited = paragraphs.clone();
+    edited[3] = "Paragraph three";
+    let ratio = similarity(&original, &page(&edited));
+    assert!(ratio < 1.0);
+    assert!(!worth_reindexing(ratio));
+    // Half of the page rewritten
+    let rewritten: Vec<&str> = paragraphs.iter().enumerate().map(|(i, p)| if i % 2 == 0 { "New" } else { p }).collect();
+    assert!(worth_reindexing(similarity(&original, &page(&rewritten))));
+  }
+
+  #[test]
+  fn diffs_the_two_latest_copies_by_default() {
+    let timestamps: Vec<String> = ["20240101000000", "20240201000000", "20240301000000"].map(String::from).to_vec();
+    let pick = |from, to| pick(&timestamps, from, to, "https://example.com/");
+    assert_eq!(pick(None, None).unwrap(), ("20240201000000".to_string(), "20240301000000".to_string()));
+    assert_eq!(pick(None, Some("20240201000000")).unwrap().0, "20240101000000");
+    assert_eq!(pick(Some("20240101000000"), None).unwrap().1, "20240301000000");
+    assert!(pick(None, Some("20240101000000")).is_err());
+    assert!(super::pick(&[], None, None, "https://example.com/").is_err());
+  }
+}
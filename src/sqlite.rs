@@ -0,0 +1,188 @@
+// SQLite storage backend.
+// Each bookmark is kept as a row whose `data` column holds its full JSON
+// representation so that no field is lost, while the other columns duplicate
+// the fields worth querying. The schema version of the JSON representation is
+// kept in the `user_version` pragma.
+use anyhow::Result;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use std::path::Path;
+
+use crate::schema;
+use crate::storage::BookmarkStore;
+use crate::Bookmark;
+
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS bookmarks (
+    hash TEXT PRIMARY KEY,
+    position INTEGER NOT NULL,
+    href TEXT NOT NULL,
+    title TEXT NOT NULL,
+    posted TEXT,
+    user TEXT,
+    referer TEXT,
+    fetched TEXT,
+    fetch_error TEXT,
+    data TEXT NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS tags (
+    hash TEXT NOT NULL REFERENCES bookmarks(hash) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    PRIMARY KEY (hash, tag)
+  );
+  CREATE TABLE IF NOT EXISTS embeddings (
+    hash TEXT PRIMARY KEY,
+    vector BLOB NOT NULL
+  );
+";
+
+pub struct SqliteStore {
+  connection: Connection,
+}
+
+// Fails on a second bookmark of the same hash rather than replacing the first
+fn insert(connection: &Connection, position: usize, bookmark: &Bookmark) -> Result<()> {
+  let fetch = bookmark.meta.fetch.as_ref();
+  let inserted = connection.execute(
+    "INSERT INTO bookmarks
+      (hash, position, href, title, posted, user, referer, fetched, fetch_error, data)
+      VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+    params![
+      bookmark.hash,
+      position as i64,
+      bookmark.href,
+      bookmark.title,
+      bookmark.meta.posted.map(|p| p.to_string()),
+      bookmark.meta.user,
+      bookmark.meta.referer,
+      fetch.map(|f| f.date.to_string()),
+      fetch.and_then(|f| f.error.clone()),
+      serde_json::to_string(bookmark)?,
+    ],
+  );
+  match inserted {
+    Err(rusqlite::Error::SqliteFailure(error, _)) if error.code == ErrorCode::ConstraintViolation => {
+      anyhow::bail!("{} is bookmarked more than once, run `bookmark dedup` first", bookmark.href)
+    }
+    inserted => inserted?,
+  };
+  connection.execute("DELETE FROM tags WHERE hash = ?1", params![bookmark.hash])?;
+  for (i, tag) in bookmark.meta.tags.iter().enumerate() {
+    connection.execute(
+      "INSERT OR IGNORE INTO tags (hash, tag, position) VALUES (?1, ?2, ?3)",
+      params![bookmark.hash, tag, i as i64],
+    )?;
+  }
+  Ok(())
+}
+
+impl SqliteStore {
+  pub fn open(path: &Path) -> Result<Self> {
+    let connection = Connection::open(path)?;
+    connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+    connection.execute_batch(SCHEMA)?;
+    let store = SqliteStore { connection };
+    // Databases created before the schema was versioned are at version 1
+    let count: i64 = store.connection.query_row("SELECT COUNT(*) FROM bookmarks", [], |row| row.get(0))?;
+    if count == 0 && store.version()? == 1 {
+      store.set_version(schema::CURRENT_VERSION)?;
+    }
+    Ok(store)
+  }
+
+  fn version(&self) -> Result<u32> {
+    let version: u32 = self.connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version.max(1))
+  }
+
+  fn set_version(&self, version: u32) -> Result<()> {
+    self.connection.execute_batch(&format!("PRAGMA user_version = {};", version))?;
+    Ok(())
+  }
+
+  pub fn load_embeddings(&self, hash: &str) -> Result<Option<Vec<f32>>> {
+    let blob: Option<Vec<u8>> = self
+      .connection
+      .query_row("SELECT vector FROM embeddings WHERE hash = ?1", params![hash], |row| row.get(0))
+      .optional()?;
+    Ok(blob.map(|blob| {
+      blob
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+    }))
+  }
+
+  pub fn save_embeddings(&self, hash: &str, embeddings: &[f32]) -> Result<()> {
+    let blob: Vec<u8> = embeddings.iter().flat_map(|e| e.to_le_bytes()).collect();
+    self.connection.execute(
+      "INSERT OR REPLACE INTO embeddings (hash, vector) VALUES (?1, ?2)",
+      params![hash, blob],
+    )?;
+    Ok(())
+  }
+
+  // Returns the hashes of the embeddings of no bookmark
+  pub fn orphan_embeddings(&self) -> Result<Vec<String>> {
+    let mut statement = self
+      .connection
+      .prepare("SELECT hash FROM embeddings WHERE hash NOT IN (SELECT hash FROM bookmarks) ORDER BY hash")?;
+    let hashes = statement.query_map([], |row| row.get(0))?.collect::<Result<Vec<String>, _>>()?;
+    Ok(hashes)
+  }
+
+  pub fn delete_embeddings(&self, hash: &str) -> Result<()> {
+    self.connection.execute("DELETE FROM embeddings WHERE hash = ?1", params![hash])?;
+    Ok(())
+  }
+
+  pub fn has_embeddings(&self, hash: &str) -> Result<bool> {
+    Ok(
+      self
+        .connection
+        .query_row("SELECT 1 FROM embeddings WHERE hash = ?1", params![hash], |_| Ok(()))
+        .optional()?
+        .is_some(),
+    )
+  }
+}
+
+impl BookmarkStore for SqliteStore {
+  fn load_versioned(&self) -> Result<(u32, Vec<Bookmark>)> {
+    let version = self.version()?;
+    schema::check_version(version)?;
+    let mut statement = self.connection.prepare("SELECT data FROM bookmarks ORDER BY position")?;
+    let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+    let mut bookmarks = vec![];
+    for data in rows {
+      bookmarks.push(schema::upgrade(version, serde_json::from_str(&data?)?)?);
+    }
+    Ok((version, bookmarks))
+  }
+
+  fn save(&self, bookmarks: &[Bookmark]) -> Result<()> {
+    let transaction = self.connection.unchecked_transaction()?;
+    transaction.execute("DELETE FROM tags", [])?;
+    transaction.execute("DELETE FROM bookmarks", [])?;
+    for (position, bookmark) in bookmarks.iter().enumerate() {
+      insert(&transaction, position, bookmark)?;
+    }
+    transaction.execute_batch(&format!("PRAGMA user_version = {};", schema::CURRENT_VERSION))?;
+    transaction.commit()?;
+    Ok(())
+  }
+
+  fn append(&self, bookmarks: &[Bookmark], added: usize) -> Result<()> {
+    match bookmarks.len().checked_sub(added) {
+      Some(first) if self.version()? == schema::CURRENT_VERSION => {
+        let transaction = self.connection.unchecked_transaction()?;
+        for (position, bookmark) in bookmarks.iter().enumerate().skip(first) {
+          insert(&transaction, position, bookmark)?;
+        }
+        transaction.commit()?;
+        Ok(())
+      }
+      _ => self.save(bookmarks),
+    }
+  }
+}
@@ -0,0 +1,152 @@
+// Statistics about the bookmark collection and its data folder.
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+
+use crate::{domain_of, layout, Bookmark, UrlStore};
+
+// The number of domains reported
+const TOP_DOMAINS: usize = 10;
+
+#[derive(Serialize)]
+pub struct DomainCount {
+  pub domain: String,
+  pub bookmarks: usize,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+  pub total: usize,
+  // Bookmarks with a stored copy of their page
+  pub stored: usize,
+  // Bookmarks without a stored copy whose last fetch failed
+  pub failed: usize,
+  // The other bookmarks without a stored copy
+  pub missing: usize,
+  pub archived: usize,
+  pub dead_links: usize,
+  pub embeddings: usize,
+  // Bookmarks posted each month (YYYY-MM)
+  pub months: BTreeMap<String, usize>,
+  pub domains: Vec<DomainCount>,
+  pub users: BTreeMap<String, usize>,
+  // Size in bytes of the stored pages, embeddings and dated copies
+  pub disk_usage: u64,
+  // The directories of the data folder belonging to no bookmark
+  pub orphans: Vec<PathBuf>,
+  pub orphans_size: u64,
+}
+
+// Returns the number of bookmarks added by each user, "-" counting the unknown
+// ones. The report sorts them, most active first.
+pub fn users(bookmarks: &[Bookmark]) -> BTreeMap<String, usize> {
+  let mut users = BTreeMap::new();
+  for bookmark in bookmarks {
+    *users.entry(bookmark.meta.user.clone().unwrap_or("-".to_string())).or_default() += 1;
+  }
+  users
+}
+
+pub fn collect(url_store: &UrlStore, bookmarks: &[Bookmark]) -> Result<Stats> {
+  let mut stats = Stats {
+    total: bookmarks.len(),
+    stored: 0,
+    failed: 0,
+    missing: 0,
+    archived: 0,
+    dead_links: 0,
+    embeddings: 0,
+    months: BTreeMap::new(),
+    domains: vec![],
+    users: users(bookmarks),
+    disk_usage: 0,
+    orphans: vec![],
+    orphans_size: 0,
+  };
+  let mut domains: HashMap<String, usize> = HashMap::new();
+  for bookmark in bookmarks {
+    if url_store.has(&bookmark.href) {
+      stats.stored += 1;
+    } else if bookmark.meta.fetch.as_ref().is_some_and(|fetch| fetch.error.is_some()) {
+      stats.failed += 1;
+    } else {
+      stats.missing += 1;
+    }
+    stats.archived += bookmark.meta.archived as usize;
+    stats.dead_links += bookmark.meta.link.as_ref().is_some_and(|link| link.is_dead()) as usize;
+    stats.embeddings += url_store.has_embeddings(&bookmark.href) as usize;
+    if let Some(posted) = bookmark.meta.posted {
+      *stats.months.entry(posted.format("%Y-%m").to_string()).or_default() += 1;
+    }
+    *domains.entry(domain_of(&bookmark.href)).or_default() += 1;
+  }
+  let mut domains: Vec<DomainCount> =
+    domains.into_iter().map(|(domain, bookmarks)| DomainCount { domain, bookmarks }).collect();
+  domains.sort_by(|a, b| b.bookmarks.cmp(&a.bookmarks).then(a.domain.cmp(&b.domain)));
+  domains.truncate(TOP_DOMAINS);
+  stats.domains = domains;
+  let hashes: HashSet<&str> = bookmarks.iter().map(|b| b.hash.as_str()).collect();
+  for entry in layout::entries(&url_store.data_folder)? {
+    stats.disk_usage += entry.size;
+    if !hashes.contains(entry.hash.as_str()) {
+      stats.orphans_size += entry.size;
+      stats.orphans.push(entry.path);
+    }
+  }
+  Ok(stats)
+}
+
+// Formats a size in bytes for humans
+pub fn human_size(bytes: u64) -> String {
+  const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+  if bytes < 1024 {
+    return format!("{} B", bytes);
+  }
+  let mut size = bytes as f64 / 1024.0;
+  let mut unit = 0;
+  while size >= 1024.0 && unit < UNITS.len() - 1 {
+    size /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn print_table(heading: &str, rows: &[(String, usize)]) {
+  let width = rows.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0).max(heading.len());
+  println!();
+  println!("{:width$}  bookmarks", heading);
+  for (name, count) in rows {
+    println!("{:width$}  {}", name, count);
+  }
+}
+
+// Prints the statistics as tables, or as JSON
+pub fn print(stats: &Stats, json: bool) -> Result<()> {
+  if json {
+    println!("{}", serde_json::to_string_pretty(stats)?);
+    return Ok(());
+  }
+  let summary = [
+    ("bookmarks", stats.total.to_string()),
+    ("  stored", stats.stored.to_string()),
+    ("  failed", stats.failed.to_string()),
+    ("  missing", stats.missing.to_string()),
+    ("archived", stats.archived.to_string()),
+    ("dead links", stats.dead_links.to_string()),
+    ("with embeddings", stats.embeddings.to_string()),
+    ("disk usage", human_size(stats.disk_usage)),
+    ("orphaned data", format!("{} ({})", stats.orphans.len(), human_size(stats.orphans_size))),
+  ];
+  for (name, value) in summary {
+    println!("{:16} {}", name, value);
+  }
+  let months: Vec<(String, usize)> = stats.months.iter().map(|(month, count)| (month.clone(), *count)).collect();
+  print_table("month", &months);
+  let domains: Vec<(String, usize)> = stats.domains.iter().map(|d| (d.domain.clone(), d.bookmarks)).collect();
+  print_table("domain", &domains);
+  let mut users: Vec<(String, usize)> = stats.users.iter().map(|(user, count)| (user.clone(), *count)).collect();
+  users.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+  print_table("user", &users);
+  Ok(())
+}
@@ -0,0 +1,468 @@
+// Reading and writing the bookmark file.
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::schema::{self, Envelope};
+use crate::sqlite::SqliteStore;
+use crate::{Bookmark, Config};
+
+// The number of backups kept when the configuration does not say otherwise
+pub const DEFAULT_BACKUPS: usize = 3;
+
+// The formats the bookmark file can be written in
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+  // The whole list on a single line
+  Json,
+  // An indented list, one field per line
+  PrettyJson,
+  // One bookmark per line. New bookmarks are appended to the file.
+  JsonLines,
+  Yaml,
+  Toml,
+  // A SQLite database, see sqlite.rs
+  Sqlite,
+}
+
+// The storage backends, as chosen on the command line
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Backend {
+  Json,
+  Sqlite,
+}
+
+// Where the bookmarks are persisted
+pub trait BookmarkStore {
+  // Returns the bookmarks, upgraded to the current schema, along with the
+  // schema version they were stored in.
+  fn load_versioned(&self) -> Result<(u32, Vec<Bookmark>)>;
+  fn load(&self) -> Result<Vec<Bookmark>> {
+    Ok(self.load_versioned()?.1)
+  }
+  fn save(&self, bookmarks: &[Bookmark]) -> Result<()>;
+  // Persists the bookmarks after `added` new bookmarks were pushed at the end
+  // of the list. Stores which can, only write the new bookmarks.
+  fn append(&self, bookmarks: &[Bookmark], _added: usize) -> Result<()> {
+    self.save(bookmarks)
+  }
+}
+
+// A bookmark file in one of the text formats
+pub struct FileStore {
+  path: PathBuf,
+  format: Format,
+  backups: usize,
+}
+
+// Guesses the format of a bookmark file from its extension.
+pub fn guess_format(path: &Path) -> Format {
+  match path.extension().and_then(|e| e.to_str()) {
+    Some("jsonl") | Some("ndjson") => Format::JsonLines,
+    Some("yaml") | Some("yml") => Format::Yaml,
+    Some("toml") => Format::Toml,
+    Some("db") | Some("sqlite") | Some("sqlite3") => Format::Sqlite,
+    _ => Format::Json,
+  }
+}
+
+// Returns the format of the bookmark file: the configured one or, if none,
+// the one guessed from the file extension.
+pub fn format(config: &Config) -> Format {
+  config.format.unwrap_or_else(|| guess_format(&config.bookmarks))
+}
+
+// Opens the store for a bookmark file in the given format.
+pub fn open_store(path: &Path, format: Format, backups: usize) -> Result<Box<dyn BookmarkStore>> {
+  Ok(match format {
+    Format::Sqlite => Box::new(SqliteStore::open(path)?),
+    _ => Box::new(FileStore { path: path.to_path_buf(), format, backups }),
+  })
+}
+
+// Opens the store of the configured bookmark file.
+pub fn open(config: &Config) -> Result<Box<dyn BookmarkStore>> {
+  open_store(&config.bookmarks, format(config), config.backups.unwrap_or(DEFAULT_BACKUPS))
+}
+
+// Returns whether a JSON Lines line is the header holding the schema version
+fn is_header(line: &Value) -> bool {
+  line.get("version").is_some() && line.get("href").is_none()
+}
+
+fn parse(format: Format, content: &str) -> Result<(u32, Vec<Bookmark>)> {
+  if content.trim().is_empty() {
+    // serde does not accept empty files
+    return Ok((schema::CURRENT_VERSION, vec![]));
+  }
+  let document: Value = match format {
+    Format::Json | Format::PrettyJson => serde_json::from_str(content)?,
+    Format::JsonLines => {
+      let mut lines = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| serde_json::from_str(line).with_context(|| format!("line {}", i + 1)))
+        .collect::<Result<Vec<Value>>>()?;
+      // The header is optional, files without one are bare lists
+      match lines.first() {
+        Some(header) if is_header(header) => {
+          let mut header = lines.remove(0);
+          header["bookmarks"] = Value::Array(lines);
+          header
+        }
+        _ => Value::Array(lines),
+      }
+    }
+    Format::Yaml => serde_json::to_value(serde_yaml::from_str::<serde_yaml::Value>(content)?)?,
+    Format::Toml => {
+      let mut document = serde_json::to_value(toml::from_str::<toml::Value>(content)?)?;
+      // TOML files always had an envelope but it was not versioned at first.
+      // An empty list of bookmarks was left out.
+      if document.get("version").is_none() {
+        document = match document.get_mut("bookmarks") {
+          Some(bookmarks) => bookmarks.take(),
+          None => Value::Array(vec![]),
+        };
+      }
+      document
+    }
+    Format::Sqlite => anyhow::bail!("not a text format"),
+  };
+  schema::load(document)
+}
+
+// Loads the bookmark file, creating it if it does not exist.
+pub fn load_bookmarks(config: &Config) -> Result<Vec<Bookmark>> {
+  open(config)?.load()
+}
+
+// An advisory lock on the bookmark file, released when dropped.
+pub struct Lock {
+  file: File,
+}
+
+impl Drop for Lock {
+  fn drop(&mut self) {
+    let _ = self.file.unlock();
+  }
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+  let mut name = path.file_name().unwrap_or_default().to_os_string();
+  name.push(suffix);
+  path.with_file_name(name)
+}
+
+// Takes an exclusive lock on the bookmark file so that concurrent invocations
+// do not lose each other's changes. The lock is taken on a `<file>.lock`
+// sibling file as the bookmark file itself is replaced on every write.
+// Blocks until the lock is available.
+pub fn lock(path: &Path) -> Result<Lock> {
+  let lock_path = sibling(path, ".lock");
+  let file = std::fs::OpenOptions::new()
+    .create(true)
+    .truncate(false)
+    .write(true)
+    .open(&lock_path)
+    .with_context(|| format!("could not open {}", lock_path.display()))?;
+  if file.try_lock().is_err() {
+    eprintln!("waiting for {} to be released...", lock_path.display());
+    file.lock()?;
+  }
+  Ok(Lock { file })
+}
+
+// Shifts the backups of the file (<file>.bak.1 being the most recent) and
+// copies the current file as the most recent backup.
+fn rotate_backups(path: &Path, backups: usize) -> Result<()> {
+  if backups == 0 || !path.exists() {
+    return Ok(());
+  }
+  for i in (1..backups).rev() {
+    let from = sibling(path, &format!(".bak.{}", i));
+    if from.exists() {
+      std::fs::rename(&from, sibling(path, &format!(".bak.{}", i + 1)))?;
+    }
+  }
+  std::fs::copy(path, sibling(path, ".bak.1"))?;
+  Ok(())
+}
+
+// Replaces the content of a file without ever leaving it half written: the
+// content is written to a temporary file, flushed to disk and then renamed
+// over the target.
+pub fn write_atomic(
+  path: &Path,
+  backups: usize,
+  write: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+  let tmp_path = sibling(path, ".tmp");
+  let file = File::create(&tmp_path)
+    .with_context(|| format!("could not create {}", tmp_path.display()))?;
+  let mut writer = std::io::BufWriter::new(file);
+  write(&mut writer)?;
+  let file = writer.into_inner()?;
+  file.sync_all()?;
+  drop(file);
+  rotate_backups(path, backups)?;
+  std::fs::rename(&tmp_path, path)
+    .with_context(|| format!("could not replace {}", path.display()))?;
+  // Make the rename itself durable
+  if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+    if let Ok(dir) = File::open(parent) {
+      let _ = dir.sync_all();
+    }
+  }
+  Ok(())
+}
+
+fn jsonl_header() -> Value {
+  serde_json::json!({ "version": schema::CURRENT_VERSION })
+}
+
+fn serialize(format: Format, bookmarks: &[Bookmark], writer: &mut dyn Write) -> Result<()> {
+  let envelope = Envelope::new(bookmarks);
+  match format {
+    Format::Json => serde_json::to_writer(writer, &envelope)?,
+    Format::PrettyJson => {
+      serde_json::to_writer_pretty(&mut *writer, &envelope)?;
+      writeln!(writer)?;
+    }
+    Format::JsonLines => {
+      serde_json::to_writer(&mut *writer, &jsonl_header())?;
+      writeln!(writer)?;
+      for bookmark in bookmarks {
+        serde_json::to_writer(&mut *writer, bookmark)?;
+        writeln!(writer)?;
+      }
+    }
+    Format::Yaml => serde_yaml::to_writer(writer, &envelope)?,
+    Format::Toml => {
+      let content = toml::to_string(&envelope)?;
+      writer.write_all(content.as_bytes())?;
+    }
+    Format::Sqlite => anyhow::bail!("not a text format"),
+  }
+  Ok(())
+}
+
+impl BookmarkStore for FileStore {
+  fn load_versioned(&self) -> Result<(u32, Vec<Bookmark>)> {
+    let mut inputfile = std::fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&self.path)?;
+    let mut content = String::new();
+    inputfile.read_to_string(&mut content)?;
+    parse(self.format, &content).with_context(|| format!("could not parse {:?} file", self.format))
+  }
+
+  fn save(&self, bookmarks: &[Bookmark]) -> Result<()> {
+    let format = self.format;
+    write_atomic(&self.path, self.backups, |writer| serialize(format, bookmarks, writer))
+  }
+
+  // JSON Lines files at the current schema version only get the new bookmarks
+  // appended, the other files are rewritten. As this writes in place, the
+  // file is backed up first and flushed to disk after.
+  fn append(&self, bookmarks: &[Bookmark], added: usize) -> Result<()> {
+    match bookmarks.len().checked_sub(added) {
+      Some(first) if self.format == Format::JsonLines && jsonl_is_current(&self.path)? => {
+        rotate_backups(&self.path, self.backups)?;
+        let mut file = std::fs::OpenOptions::new().append(true).open(&self.path)?;
+        // Make sure we start on a new line even if the file was edited by hand
+        let mut last = [0u8];
+        let mut reader = File::open(&self.path)?;
+        std::io::Seek::seek(&mut reader, std::io::SeekFrom::End(-1))?;
+        reader.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+          writeln!(file)?;
+        }
+        let mut lines = String::new();
+        for bookmark in &bookmarks[first..] {
+          lines.push_str(&serde_json::to_string(bookmark)?);
+          lines.push('\n');
+        }
+        file.write_all(lines.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+      }
+      _ => self.save(bookmarks),
+    }
+  }
+}
+
+// Returns whether a JSON Lines file starts with a header of the current schema
+// version
+fn jsonl_is_current(path: &Path) -> Result<bool> {
+  let Ok(file) = File::open(path) else {
+    return Ok(false);
+  };
+  let mut first_line = String::new();
+  std::io::BufRead::read_line(&mut std::io::BufReader::new(file), &mut first_line)?;
+  Ok(
+    serde_json::from_str::<Value>(&first_line)
+      .is_ok_and(|header| is_header(&header) && header["version"] == schema::CURRENT_VERSION),
+  )
+}
+
+// Writes bookmarks to the configured bookmark file.
+// The fields are always written in the same order so that the file can be
+// diffed.
+pub fn write_bookmarks(config: &Config, bookmarks: &[Bookmark]) -> Result<()> {
+  open(config)?.save(bookmarks)
+}
+
+// Persists the bookmarks after `added` new bookmarks were pushed at the end of
+// the list.
+pub fn append_bookmarks(config: &Config, bookmarks: &[Bookmark], added: usize) -> Result<()> {
+  open(config)?.append(bookmarks, added)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TEXT_FORMATS: [Format; 5] = [Format::Json, Format::PrettyJson, Format::JsonLines, Format::Yaml, Format::Toml];
+
+  // The files as written before the schema was versioned
+  fn v1_file(format: Format) -> &'static str {
+    match format {
+      Format::Json | Format::PrettyJson => {
+        r#"[{"href":"https://example.com/","meta":{"posted":"2024-01-01T00:00:00","tags":["a"]},"title":"Example"}]"#
+      }
+      Format::JsonLines => r#"{"href":"https://example.com/","meta":{"posted":"2024-01-01T00:00:00","tags":["a"]},"title":"Example"}"#,
+      Format::Yaml => "- href: https://example.com/\n  meta:\n    posted: 2024-01-01T00:00:00\n    tags: [a]\n  title: Example\n",
+      Format::Toml => {
+        "[[bookmarks]]\nhref = \"https://example.com/\"\ntitle = \"Example\"\n\n[bookmarks.meta]\nposted = \"2024-01-01T00:00:00\"\ntags = [\"a\"]\n"
+      }
+      Format::Sqlite => unreachable!(),
+    }
+  }
+
+  #[test]
+  fn loads_and_upgrades_v1_files() {
+    for format in TEXT_FORMATS {
+      let (version, bookmarks) = parse(format, v1_file(format)).unwrap();
+      assert_eq!(version, 1, "{:?}", format);
+      assert_eq!(bookmarks.len(), 1, "{:?}", format);
+      assert_eq!(bookmarks[0].href, "https://example.com/");
+      assert_eq!(bookmarks[0].hash, crate::get_hash("https://example.com/"));
+      assert_eq!(bookmarks[0].meta.tags, vec!["a"]);
+      // Saving writes the current version
+      let mut content = vec![];
+      serialize(format, &bookmarks, &mut content).unwrap();
+      let (version, upgraded) = parse(format, &String::from_utf8(content).unwrap()).unwrap();
+      assert_eq!(version, schema::CURRENT_VERSION, "{:?}", format);
+      assert_eq!(upgraded.len(), 1, "{:?}", format);
+      assert_eq!(upgraded[0].title, "Example");
+    }
+  }
+
+  #[test]
+  fn loads_a_toml_file_without_bookmarks() {
+    let (version, bookmarks) = parse(Format::Toml, "# no bookmarks yet\n").unwrap();
+    assert_eq!(version, 1);
+    assert!(bookmarks.is_empty());
+  }
+
+  fn bookmarks() -> Vec<Bookmark> {
+    let mut first = crate::tests::bookmark("https://example.com/a", "2024-01-01");
+    first.title = "A \"quoted\" title, été".to_string();
+    first.meta.tags = vec!["rust".to_string(), "web".to_string()];
+    first.meta.user = Some("alice".to_string());
+    first.meta.referer = Some("https://news.ycombinator.com/".to_string());
+    first.meta.read = first.meta.posted;
+    first.meta.starred = true;
+    first.meta.notes = Some("# Notes\n\nOn two lines".to_string());
+    first.extra.insert("custom".to_string(), serde_json::json!({ "nested": [1, 2] }));
+    let second = crate::tests::bookmark("https://example.com/b", "2024-02-01");
+    vec![first, second]
+  }
+
+  #[test]
+  fn every_format_round_trips() {
+    let dir = tempfile::tempdir().unwrap();
+    for format in TEXT_FORMATS.into_iter().chain([Format::Sqlite]) {
+      let store = open_store(&dir.path().join(format!("{:?}", format)), format, 2).unwrap();
+      store.save(&bookmarks()).unwrap();
+      let loaded = store.load().unwrap();
+      assert_eq!(
+        serde_json::to_value(&loaded).unwrap(),
+        serde_json::to_value(bookmarks()).unwrap(),
+        "{:?}",
+        format
+      );
+      assert_eq!(loaded[1].hash, crate::get_hash("https://example.com/b"));
+    }
+  }
+
+  #[test]
+  fn appends_the_new_bookmarks() {
+    let dir = tempfile::tempdir().unwrap();
+    for format in [Format::JsonLines, Format::Json, Format::Sqlite] {
+      let store = open_store(&dir.path().join(format!("{:?}", format)), format, 0).unwrap();
+      let mut bookmarks = bookmarks();
+      let added = bookmarks.split_off(1);
+      store.save(&bookmarks).unwrap();
+      bookmarks.extend(added);
+      store.append(&bookmarks, 1).unwrap();
+      let hrefs: Vec<String> = store.load().unwrap().into_iter().map(|b| b.href).collect();
+      assert_eq!(hrefs, ["https://example.com/a", "https://example.com/b"], "{:?}", format);
+    }
+  }
+
+  #[test]
+  fn rotates_the_backups() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bookmarks.json");
+    for content in ["1", "2", "3", "4"] {
+      write_atomic(&path, 2, |writer| Ok(writer.write_all(content.as_bytes())?)).unwrap();
+    }
+    let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+    assert_eq!(read("bookmarks.json"), "4");
+    assert_eq!(read("bookmarks.json.bak.1"), "3");
+    assert_eq!(read("bookmarks.json.bak.2"), "2");
+    assert!(!dir.path().join("bookmarks.json.bak.3").exists());
+    assert!(!dir.path().join("bookmarks.json.tmp").exists());
+  }
+
+  #[test]
+  fn sqlite_refuses_the_duplicate_hashes() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = open_store(&dir.path().join("bookmarks.db"), Format::Sqlite, 0).unwrap();
+    store.save(&bookmarks()).unwrap();
+    let mut duplicated = bookmarks();
+    duplicated.push(crate::tests::bookmark("https://example.com/a", "2024-03-01"));
+    let error = store.save(&duplicated).unwrap_err();
+    assert!(error.to_string().contains("https://example.com/a is bookmarked more than once"), "{}", error);
+    assert!(store.append(&duplicated, 1).is_err());
+    // Both are rolled back
+    assert_eq!(
+      serde_json::to_value(store.load().unwrap()).unwrap(),
+      serde_json::to_value(bookmarks()).unwrap()
+    );
+  }
+
+  #[test]
+  fn backs_up_the_json_lines_file_before_appending() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bookmarks.jsonl");
+    let store = open_store(&path, Format::JsonLines, 2).unwrap();
+    let mut bookmarks = bookmarks();
+    let added = bookmarks.split_off(1);
+    store.save(&bookmarks).unwrap();
+    let before = std::fs::read_to_string(&path).unwrap();
+    bookmarks.extend(added);
+    store.append(&bookmarks, 1).unwrap();
+    assert_eq!(std::fs::read_to_string(dir.path().join("bookmarks.jsonl.bak.1")).unwrap(), before);
+    assert!(std::fs::read_to_string(&path).unwrap().starts_with(&before));
+  }
+}
@@ -0,0 +1,506 @@
+// The terminal interface of `bookmark tui`: the bookmarks filtered as the query
+// is typed, fuzzily or with the semantic search, next to a preview of the
+// stored article.
+// Like the server, the bookmark file is only locked to apply a change, so that
+// the command line can still be used meanwhile.
+use anyhow::Result;
+use base64::Engine;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+
+use crate::{git, open_in_browser, semantic_search, storage, Bookmark, Config, Embedder, Filter, UrlStore};
+
+const HELP: &str = "enter open · ^y copy url · ^t tag · ^r read · ^d delete · ^s semantic · esc quit";
+
+// The semantic search waits for this pause in the typing
+const SEARCH_DELAY: Duration = Duration::from_millis(300);
+
+// What the keys are typed into
+enum Prompt {
+  Query,
+  // Tags to add, or to remove when prefixed with "-"
+  Tags(String),
+  DeleteConfirmation,
+}
+
+// What a key does, depending on the prompt
+#[derive(Debug, PartialEq)]
+enum Action {
+  Quit,
+  // Back to the query
+  Cancel,
+  Type(char),
+  Erase,
+  Move(isize),
+  Open,
+  Copy,
+  // Asks for the tags of the selected bookmark
+  Tag,
+  // Applies the tags typed
+  ApplyTags,
+  ToggleRead,
+  // Asks to confirm the deletion of the selected bookmark
+  Delete,
+  // Whether the deletion is confirmed
+  Confirm(bool),
+  ToggleSemantic,
+  Nothing,
+}
+
+fn action(prompt: &Prompt, key: KeyEvent) -> Action {
+  let control = key.modifiers.contains(KeyModifiers::CONTROL);
+  match (prompt, key.code) {
+    (_, KeyCode::Char('c')) if control => Action::Quit,
+    (Prompt::Tags(_) | Prompt::DeleteConfirmation, KeyCode::Esc) => Action::Cancel,
+    (Prompt::Tags(_), KeyCode::Char(c)) => Action::Type(c),
+    (Prompt::Tags(_), KeyCode::Backspace) => Action::Erase,
+    (Prompt::Tags(_), KeyCode::Enter) => Action::ApplyTags,
+    (Prompt::Tags(_), _) => Action::Nothing,
+    (Prompt::DeleteConfirmation, code) => Action::Confirm(code == KeyCode::Char('y')),
+    (Prompt::Query, KeyCode::Esc) => Action::Quit,
+    (Prompt::Query, KeyCode::Up) => Action::Move(-1),
+    (Prompt::Query, KeyCode::Down) => Action::Move(1),
+    (Prompt::Query, KeyCode::PageUp) => Action::Move(-10),
+    (Prompt::Query, KeyCode::PageDown) => Action::Move(10),
+    (Prompt::Query, KeyCode::Char('p')) if control => Action::Move(-1),
+    (Prompt::Query, KeyCode::Char('n')) if control => Action::Move(1),
+    (Prompt::Query, KeyCode::Enter) => Action::Open,
+    (Prompt::Query, KeyCode::Char('y')) if control => Action::Copy,
+    (Prompt::Query, KeyCode::Char('t')) if control => Action::Tag,
+    (Prompt::Query, KeyCode::Char('r')) if control => Action::ToggleRead,
+    (Prompt::Query, KeyCode::Char('d')) if control => Action::Delete,
+    (Prompt::Query, KeyCode::Char('s')) if control => Action::ToggleSemantic,
+    (Prompt::Query, KeyCode::Char(c)) if !control => Action::Type(c),
+    (Prompt::Query, KeyCode::Backspace) => Action::Erase,
+    _ => Action::Nothing,
+  }
+}
+
+// The positions of the bookmarks shown without a query, most recent first.
+// The archived ones are hidden as in the listing.
+fn candidates(config: &Config, bookmarks: &[Bookmark]) -> Vec<usize> {
+  let filter = Filter::default();
+  (0..bookmarks.len()).rev().filter(|&i| filter.matches(config, &bookmarks[i])).collect()
+}
+
+// The candidates matching the query fuzzily on the title, url and tags, best
+// match first
+fn rank(bookmarks: &[Bookmark], candidates: Vec<usize>, query: &str) -> Vec<usize> {
+  if query.is_empty() {
+    return candidates;
+  }
+  let matcher = SkimMatcherV2::default();
+  let mut scores: Vec<(i64, usize)> = candidates
+    .into_iter()
+    .filter_map(|i| {
+      let bookmark = &bookmarks[i];
+      let haystack = format!("{} {} {}", bookmark.title, bookmark.href, bookmark.meta.tags.join(" "));
+      matcher.fuzzy_match(&haystack, query).map(|score| (score, i))
+    })
+    .collect();
+  // Stable, so that the most recent comes first among equal scores
+  scores.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+  scores.into_iter().map(|(_, i)| i).collect()
+}
+
+struct App<'a> {
+  config: &'a Config,
+  url_store: UrlStore<'a>,
+  bookmarks: Vec<Bookmark>,
+  query: String,
+  semantic: bool,
+  // Whether the semantic search has to run again once the typing pauses
+  search_pending: bool,
+  // The positions in bookmarks of the shown ones, best match first
+  shown: Vec<usize>,
+  list: ListState,
+  prompt: Prompt,
+  message: Option<String>,
+  previews: HashMap<String, String>,
+  // Loaded when the semantic search is first enabled
+  embedder: Option<Embedder>,
+  embeddings: HashMap<String, Option<Vec<f32>>>,
+}
+
+impl<'a> App<'a> {
+  fn new(config: &'a Config) -> Result<Self> {
+    let mut app = App {
+      config,
+      url_store: UrlStore::new(config)?,
+      bookmarks: storage::load_bookmarks(config)?,
+      query: String::new(),
+      semantic: false,
+      search_pending: false,
+      shown: vec![],
+      list: ListState::default(),
+      prompt: Prompt::Query,
+      message: None,
+      previews: HashMap::new(),
+      embedder: None,
+      embeddings: HashMap::new(),
+    };
+    app.filter();
+    Ok(app)
+  }
+
+  fn selected(&self) -> Option<&Bookmark> {
+    self.list.selected().and_then(|i| self.shown.get(i)).map(|&i| &self.bookmarks[i])
+  }
+
+  fn filter(&mut self) {
+    if self.semantic && !self.query.is_empty() {
+      // Updated by search once the typing pauses
+      self.search_pending = true;
+      return;
+    }
+    self.shown = rank(&self.bookmarks, candidates(self.config, &self.bookmarks), &self.query);
+    self.select_first();
+  }
+
+  fn select_first(&mut self) {
+    self.list.select(if self.shown.is_empty() { None } else { Some(0) });
+  }
+
+  fn search(&mut self) {
+    self.search_pending = false;
+    if self.query.is_empty() {
+      return;
+    }
+    if self.embedder.is_none() {
+      match Embedder::new() {
+        Ok(embedder) => self.embedder = Some(embedder),
+        Err(e) => {
+          self.message = Some(format!("semantic search unavailable: {}", e));
+          self.semantic = false;
+          self.filter();
+          return;
+        }
+      }
+    }
+    let candidates = candidates(self.config, &self.bookmarks);
+    // Loaded before the search as the closure cannot borrow the cache mutably
+    for &i in &candidates {
+      let href = &self.bookmarks[i].href;
+      if !self.embeddings.contains_key(href) {
+        let embeddings = self.url_store.load_embeddings(href).unwrap_or(None);
+        self.embeddings.insert(href.clone(), embeddings);
+      }
+    }
+    let bookmarks: Vec<&Bookmark> = candidates.iter().map(|&i| &self.bookmarks[i]).collect();
+    let result = semantic_search(self.embedder.as_ref().unwrap(), &bookmarks, &self.query, |bookmark| {
+      Ok(self.embeddings.get(&bookmark.href).cloned().flatten())
+    });
+    match result {
+      Ok(similarities) => {
+        let positions: HashMap<&str, usize> = candidates.iter().map(|&i| (self.bookmarks[i].hash.as_str(), i)).collect();
+        self.shown = similarities.iter().map(|(_, bookmark)| positions[bookmark.hash.as_str()]).collect();
+      }
+      Err(e) => self.message = Some(format!("search failed: {}", e)),
+    }
+    self.select_first();
+  }
+
+  // Applies a change to the bookmark file as it is now and commits it
+  fn change(&mut self, description: String, apply: impl FnOnce(&mut Vec<Bookmark>)) -> Result<()> {
+    let _lock = storage::lock(&self.config.bookmarks)?;
+    let mut bookmarks = storage::load_bookmarks(self.config)?;
+    apply(&mut bookmarks);
+    storage::write_bookmarks(self.config, &bookmarks)?;
+    if git::enabled(self.config) {
+      git::commit(self.config, &description)?;
+    }
+    self.bookmarks = bookmarks;
+    self.message = Some(description);
+    let selected = self.list.selected();
+    if self.semantic && !self.query.is_empty() {
+      self.search();
+    } else {
+      self.filter();
+    }
+    self.list.select(selected.filter(|&i| i < self.shown.len()).or(self.shown.len().checked_sub(1)));
+    Ok(())
+  }
+
+  // Copies the url with the escape sequence of the terminals for the clipboard
+  fn copy(&mut self, url: &str) -> Result<()> {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(url);
+    let mut stdout = std::io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", encoded)?;
+    stdout.flush()?;
+    self.message = Some(format!("copied {}", url));
+    Ok(())
+  }
+
+  fn tag(&mut self, hash: String, tags: &str) -> Result<()> {
+    let tags: Vec<String> = tags.split_whitespace().map(str::to_string).collect();
+    if tags.is_empty() {
+      return Ok(());
+    }
+    self.change(format!("tag {}: {}", hash, tags.join(" ")), |bookmarks| {
+      if let Some(bookmark) = bookmarks.iter_mut().find(|b| b.hash == hash) {
+        for tag in tags {
+          match tag.strip_prefix('-') {
+            Some(tag) => bookmark.meta.tags.retain(|t| t != tag),
+            None if !bookmark.meta.tags.contains(&tag) => bookmark.meta.tags.push(tag),
+            None => (),
+          }
+        }
+      }
+    })
+  }
+
+  fn toggle_read(&mut self, hash: String) -> Result<()> {
+    let read = self.selected().is_some_and(|b| b.meta.read.is_some());
+    let description = format!("{} {}", if read { "unread" } else { "read" }, hash);
+    self.change(description, |bookmarks| {
+      if let Some(bookmark) = bookmarks.iter_mut().find(|b| b.hash == hash) {
+        bookmark.meta.read = if read { None } else { Some(chrono::offset::Utc::now().naive_utc()) };
+      }
+    })
+  }
+
+  fn delete(&mut self, bookmark: &Bookmark) -> Result<()> {
+    let hash = bookmark.hash.clone();
+    self.change(format!("remove {} ({})", bookmark.title, bookmark.href), |bookmarks| {
+      bookmarks.retain(|b| b.hash != hash)
+    })
+  }
+
+  fn move_selection(&mut self, offset: isize) {
+    if let Some(selected) = self.list.selected() {
+      let last = self.shown.len().saturating_sub(1);
+      self.list.select(Some(selected.saturating_add_signed(offset).min(last)));
+    }
+  }
+
+  // Returns false to quit
+  fn key(&mut self, key: KeyEvent) -> Result<bool> {
+    let selected = self.selected().cloned();
+    match (action(&self.prompt, key), &mut self.prompt) {
+      (Action::Quit, _) => return Ok(false),
+      (Action::Cancel, _) => self.prompt = Prompt::Query,
+      (Action::Type(c), Prompt::Tags(tags)) => tags.push(c),
+      (Action::Erase, Prompt::Tags(tags)) => {
+        tags.pop();
+      }
+      (Action::ApplyTags, Prompt::Tags(tags)) => {
+        let tags = std::mem::take(tags);
+        self.prompt = Prompt::Query;
+        if let Some(bookmark) = selected {
+          self.tag(bookmark.hash, &tags)?;
+        }
+      }
+      (Action::Confirm(confirmed), _) => {
+        self.prompt = Prompt::Query;
+        if let (true, Some(bookmark)) = (confirmed, selected) {
+          self.delete(&bookmark)?;
+        }
+      }
+      (Action::Type(c), _) => {
+        self.query.push(c);
+        self.filter();
+      }
+      (Action::Erase, _) => {
+        self.query.pop();
+        self.filter();
+      }
+      (Action::Move(offset), _) => self.move_selection(offset),
+      (Action::Open, _) => {
+        if let Some(bookmark) = selected {
+          open_in_browser(&bookmark.href)?;
+          self.message = Some(format!("opened {}", bookmark.href));
+        }
+      }
+      (Action::Copy, _) => {
+        if let Some(bookmark) = selected {
+          self.copy(&bookmark.href)?;
+        }
+      }
+      (Action::Tag, _) if selected.is_some() => self.prompt = Prompt::Tags(String::new()),
+      (Action::ToggleRead, _) => {
+        if let Some(bookmark) = selected {
+          self.toggle_read(bookmark.hash)?;
+        }
+      }
+      (Action::Delete, _) if selected.is_some() => self.prompt = Prompt::DeleteConfirmation,
+      (Action::ToggleSemantic, _) => {
+        self.semantic = !self.semantic;
+        self.filter();
+      }
+      _ => (),
+    }
+    Ok(true)
+  }
+
+  fn preview(&mut self, bookmark: &Bookmark) -> Text<'static> {
+    let mut lines = vec![
+      Line::from(bookmark.title.clone()).bold(),
+      Line::from(bookmark.href.clone()).dark_gray(),
+    ];
+    let mut meta = vec![];
+    if let Some(posted) = bookmark.meta.posted {
+      meta.push(posted.format("%Y-%m-%d").to_string());
+    }
+    if let Some(user) = &bookmark.meta.user {
+      meta.push(user.clone());
+    }
+    if !bookmark.meta.tags.is_empty() {
+      meta.push(bookmark.meta.tags.join(", "));
+    }
+    if bookmark.meta.read.is_some() {
+      meta.push("read".to_string());
+    }
+    lines.push(Line::from(meta.join(" · ")).dark_gray());
+    if let Some(notes) = &bookmark.meta.notes {
+      lines.push(Line::default());
+      lines.extend(notes.lines().map(|line| Line::from(line.to_string()).italic()));
+    }
+    lines.push(Line::default());
+    let text = self.previews.entry(bookmark.hash.clone()).or_insert_with(|| {
+      self
+        .url_store
+        .get_text(&bookmark.href)
+        .unwrap_or("(no stored copy)".to_string())
+    });
+    lines.extend(text.lines().filter(|line| !line.is_empty()).map(|line| Line::from(line.to_string())));
+    Text::from(lines)
+  }
+
+  fn draw(&mut self, frame: &mut Frame) {
+    let [top, main, bottom] =
+      Layout::vertical([Constraint::Length(1), Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+    let [left, right] = Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(main);
+
+    let (label, input) = match &self.prompt {
+      Prompt::Query if self.semantic => ("semantic> ", self.query.as_str()),
+      Prompt::Query => ("> ", self.query.as_str()),
+      Prompt::Tags(tags) => ("tags (-tag to remove)> ", tags.as_str()),
+      Prompt::DeleteConfirmation => ("delete? (y/n) ", ""),
+    };
+    frame.render_widget(Line::from(vec![Span::raw(label).bold(), Span::raw(input)]), top);
+    frame.set_cursor_position((top.x + (label.len() + input.chars().count()) as u16, top.y));
+
+    let items: Vec<ListItem> = self
+      .shown
+      .iter()
+      .map(|&i| {
+        let bookmark = &self.bookmarks[i];
+        let title = if bookmark.title.is_empty() { &bookmark.href } else { &bookmark.title };
+        let mut spans = vec![Span::raw(title.clone())];
+        if !bookmark.meta.tags.is_empty() {
+          spans.push(Span::raw(format!(" {}", bookmark.meta.tags.join(" "))).dark_gray());
+        }
+        ListItem::new(Line::from(spans))
+      })
+      .collect();
+    let list = List::new(items)
+      .block(Block::bordered().title(format!("{}/{}", self.shown.len(), self.bookmarks.len())))
+      .highlight_style(Style::new().reversed());
+    frame.render_stateful_widget(list, left, &mut self.list);
+
+    let preview = match self.selected().cloned() {
+      Some(bookmark) => self.preview(&bookmark),
+      None => Text::default(),
+    };
+    frame.render_widget(Paragraph::new(preview).wrap(Wrap { trim: false }).block(Block::bordered()), right);
+
+    let status = match &self.message {
+      Some(message) => Line::from(message.as_str()),
+      None => Line::from(HELP).dark_gray(),
+    };
+    frame.render_widget(status, Rect { height: 1, ..bottom });
+  }
+
+  fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+    loop {
+      terminal.draw(|frame| self.draw(frame))?;
+      if self.search_pending && !event::poll(SEARCH_DELAY)? {
+        self.search();
+        continue;
+      }
+      if let Event::Key(key) = event::read()? {
+        if key.kind != KeyEventKind::Press {
+          continue;
+        }
+        self.message = None;
+        match self.key(key) {
+          Ok(true) => (),
+          Ok(false) => return Ok(()),
+          Err(e) => self.message = Some(format!("error: {:#}", e)),
+        }
+      }
+    }
+  }
+}
+
+pub fn run(config: &Config) -> Result<()> {
+  let mut app = App::new(config)?;
+  let mut terminal = ratatui::init();
+  let result = app.run(&mut terminal);
+  ratatui::restore();
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::{bookmark, config};
+
+  fn titled(href: &str, title: &str, tags: &[&str]) -> Bookmark {
+    let mut bookmark = bookmark(href, "2024-01-01");
+    bookmark.title = title.to_string();
+    bookmark.meta.tags = tags.iter().map(|tag| tag.to_string()).collect();
+    bookmark
+  }
+
+  #[test]
+  fn ranks_the_fuzzy_matches_most_recent_first() {
+    let mut bookmarks = vec![
+      titled("https://a.example/", "Rust ownership", &[]),
+      titled("https://b.example/", "Cooking pasta", &["food"]),
+      titled("https://c.example/", "Rust ownership", &[]),
+      titled("https://d.example/", "Archived rust", &[]),
+    ];
+    bookmarks[3].meta.archived = true;
+    let candidates = candidates(&config(), &bookmarks);
+    assert_eq!(candidates, vec![2, 1, 0]);
+    assert_eq!(rank(&bookmarks, candidates.clone(), ""), vec![2, 1, 0]);
+    // Equal scores keep the most recent first
+    assert_eq!(rank(&bookmarks, candidates.clone(), "rust own"), vec![2, 0]);
+    assert_eq!(rank(&bookmarks, candidates.clone(), "food"), vec![1]);
+    assert_eq!(rank(&bookmarks, candidates, "zzz"), Vec::<usize>::new());
+  }
+
+  #[test]
+  fn maps_the_keys_to_the_actions_of_the_prompt() {
+    let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+    let control = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL);
+    let query = Prompt::Query;
+    assert_eq!(action(&query, key(KeyCode::Char('t'))), Action::Type('t'));
+    assert_eq!(action(&query, control('t')), Action::Tag);
+    assert_eq!(action(&query, control('d')), Action::Delete);
+    assert_eq!(action(&query, control('n')), Action::Move(1));
+    assert_eq!(action(&query, key(KeyCode::PageUp)), Action::Move(-10));
+    assert_eq!(action(&query, key(KeyCode::Enter)), Action::Open);
+    assert_eq!(action(&query, key(KeyCode::Esc)), Action::Quit);
+    let tags = Prompt::Tags(String::new());
+    assert_eq!(action(&tags, key(KeyCode::Char('y'))), Action::Type('y'));
+    assert_eq!(action(&tags, key(KeyCode::Enter)), Action::ApplyTags);
+    assert_eq!(action(&tags, key(KeyCode::Esc)), Action::Cancel);
+    assert_eq!(action(&tags, key(KeyCode::Up)), Action::Nothing);
+    assert_eq!(action(&tags, control('c')), Action::Quit);
+    let delete = Prompt::DeleteConfirmation;
+    assert_eq!(action(&delete, key(KeyCode::Char('y'))), Action::Confirm(true));
+    assert_eq!(action(&delete, key(KeyCode::Char('n'))), Action::Confirm(false));
+    assert_eq!(action(&delete, key(KeyCode::Esc)), Action::Cancel);
+  }
+}
@@ -0,0 +1,284 @@
+// The web interface of `bookmark serve`: a paginated listing, a search box and
+// a reader for the stored copies of the pages.
+use reqwest::Url;
+use std::collections::HashMap;
+use tiny_http::Header;
+
+use crate::export::escape_html;
+use crate::server::Response;
+use crate::{Bookmark, UrlStore};
+
+// The cookie holding the token of the server, see /login
+pub const TOKEN_COOKIE: &str = "bookmark_token";
+
+const PAGE_SIZE: usize = 50;
+
+const STYLE: &str = "
+  body { font-family: sans-serif; max-width: 50em; margin: auto; padding: 1em; line-height: 1.4; }
+  form { margin-bottom: 1em; }
+  input[name=q] { width: 60%; }
+  li { margin-bottom: 0.4em; }
+  .meta { color: #777; font-size: 0.85em; }
+  nav a { margin-right: 1em; }
+";
+
+fn header(name: &str, value: &str) -> Header {
+  Header::from_bytes(name, value).unwrap()
+}
+
+fn html_response(status: u16, title: &str, body: &str) -> Response {
+  let page = format!(
+    "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head>\n<body>\n{}\n</body></html>\n",
+    escape_html(title),
+    STYLE,
+    body
+  );
+  tiny_http::Response::from_string(page)
+    .with_status_code(status)
+    .with_header(header("Content-Type", "text/html; charset=utf-8"))
+}
+
+pub fn redirect(location: &str) -> Response {
+  tiny_http::Response::from_string("")
+    .with_status_code(303)
+    .with_header(header("Location", location))
+}
+
+pub fn not_found() -> Response {
+  html_response(404, "Not found", "<p>Not found. <a href=\"/\">Back to the bookmarks</a></p>")
+}
+
+// Only the pages of the server are followed after logging in
+fn local_path(next: &str) -> &str {
+  if next.starts_with('/') && !next.starts_with("//") && !next.starts_with("/\\") {
+    next
+  } else {
+    "/"
+  }
+}
+
+// Asks for the token of the server, then goes to next
+pub fn login(failed: bool, next: &str) -> Response {
+  let message = if failed { "<p>Invalid token.</p>" } else { "" };
+  html_response(
+    401,
+    "Bookmarks",
+    &format!(
+      "{}<form action=\"/login\" method=\"post\"><input type=\"hidden\" name=\"next\" value=\"{}\"><input type=\"password\" name=\"token\" placeholder=\"Token\" autofocus> <button>Log in</button></form>",
+      message,
+      escape_html(local_path(next))
+    ),
+  )
+}
+
+// Keeps the token in a cookie and goes to next. The cookie is lax so that the
+// bookmarklet, opened from other sites, gets it. The requests changing the
+// bookmarks are posted, lax cookies are not sent with the ones of other sites.
+pub fn logged_in(token: &str, next: &str) -> Response {
+  redirect(local_path(next)).with_header(header(
+    "Set-Cookie",
+    &format!("{}={}; Path=/; HttpOnly; SameSite=Lax", TOKEN_COOKIE, token),
+  ))
+}
+
+// Returns a link to the listing with the parameters of the current page, some
+// of them replaced
+fn link(query: &HashMap<String, String>, replace: &[(&str, String)]) -> String {
+  let mut url = Url::parse("http://localhost/").unwrap();
+  {
+    let mut pairs = url.query_pairs_mut();
+    let mut keys: Vec<&String> = query.keys().collect();
+    keys.sort();
+    for key in keys {
+      if !replace.iter().any(|(name, _)| name == key) {
+        pairs.append_pair(key, &query[key]);
+      }
+    }
+    for (name, value) in replace {
+      pairs.append_pair(name, value);
+    }
+  }
+  format!("/?{}", url.query().unwrap_or_default())
+}
+
+// The href of a bookmark, escaped. Only web links are kept, a hand edited
+// `javascript:` href would run in the page.
+fn safe_href(bookmark: &Bookmark) -> String {
+  if bookmark.href.starts_with("http://") || bookmark.href.starts_with("https://") {
+    escape_html(&bookmark.href)
+  } else {
+    "#".to_string()
+  }
+}
+
+fn search_form(query: &HashMap<String, String>) -> String {
+  format!(
+    "<form action=\"/\" method=\"get\"><input name=\"q\" value=\"{}\" placeholder=\"Search\"> <label><input type=\"checkbox\" name=\"keyword\" value=\"1\"{}> keywords</label> <button>Search</button> <a href=\"/\">All</a></form>",
+    escape_html(query.get("q").map(String::as_str).unwrap_or_default()),
+    if query.contains_key("keyword") { " checked" } else { "" }
+  )
+}
+
+fn item(bookmark: &Bookmark, score: Option<f32>) -> String {
+  let title = if bookmark.title.is_empty() { &bookmark.href } else { &bookmark.title };
+  let mut meta = vec![];
+  if let Some(score) = score {
+    meta.push(format!("{:.2}", score));
+  }
+  if let Some(posted) = bookmark.meta.posted {
+    meta.push(posted.format("%Y-%m-%d").to_string());
+  }
+  if let Some(user) = &bookmark.meta.user {
+    meta.push(escape_html(user));
+  }
+  if !bookmark.meta.tags.is_empty() {
+    meta.push(escape_html(&bookmark.meta.tags.join(", ")));
+  }
+  meta.push(format!("<a href=\"/read/{0}\">stored copy</a> <a href=\"/read/{0}?text\">text</a>", bookmark.hash));
+  format!(
+    "<li><a href=\"{}\">{}</a><br><span class=\"meta\">{}</span></li>",
+    safe_href(bookmark),
+    escape_html(title),
+    meta.join(" · ")
+  )
+}
+
+// The bookmarks, most recent first
+pub fn listing(query: &HashMap<String, String>, bookmarks: &[&Bookmark]) -> Response {
+  let pages = bookmarks.len().div_ceil(PAGE_SIZE).max(1);
+  let page: usize = query.get("page").and_then(|p| p.parse().ok()).unwrap_or(1).clamp(1, pages);
+  let first = (page - 1) * PAGE_SIZE;
+  let items: String = bookmarks.iter().rev().skip(first).take(PAGE_SIZE).map(|b| item(b, None)).collect();
+  let mut nav = vec![];
+  if page > 1 {
+    nav.push(format!("<a href=\"{}\">previous</a>", escape_html(&link(query, &[("page", (page - 1).to_string())]))));
+  }
+  nav.push(format!("page {} of {}", page, pages));
+  if page < pages {
+    nav.push(format!("<a href=\"{}\">next</a>", escape_html(&link(query, &[("page", (page + 1).to_string())]))));
+  }
+  html_response(
+    200,
+    "Bookmarks",
+    &format!(
+      "{}<p class=\"meta\">{} bookmarks</p><ol start=\"{}\">{}</ol><nav>{}</nav>",
+      search_form(query),
+      bookmarks.len(),
+      first + 1,
+      items,
+      nav.join(" ")
+    ),
+  )
+}
+
+pub fn results(query: &HashMap<String, String>, results: &[(Option<f32>, &Bookmark)]) -> Response {
+  let items: String = results.iter().take(PAGE_SIZE).map(|(score, b)| item(b, *score)).collect();
+  html_response(
+    200,
+    "Search",
+    &format!("{}<p class=\"meta\">{} results</p><ol>{}</ol>", search_form(query), results.len(), items),
+  )
+}
+
+pub fn results_error(query: &HashMap<String, String>, message: &str) -> Response {
+  html_response(500, "Search", &format!("{}<p>Error: {}</p>", search_form(query), escape_html(message)))
+}
+
+// The form of the bookmarklet, prefilled with the url and the title of the page
+pub fn add_form(fields: &HashMap<String, String>, error: Option<&str>) -> Response {
+  let field = |name: &str| escape_html(fields.get(name).map(String::as_str).unwrap_or_default());
+  let message = error.map(|e| format!("<p>Error: {}</p>", escape_html(e))).unwrap_or_default();
+  html_response(
+    if error.is_some() { 400 } else { 200 },
+    "Add a bookmark",
+    &format!(
+      "<p><a href=\"/\">Bookmarks</a></p>{}<form action=\"/add\" method=\"post\">\
+       <p><input name=\"url\" value=\"{}\" placeholder=\"Url\" size=\"60\"></p>\
+       <p><input name=\"title\" value=\"{}\" placeholder=\"Title\" size=\"60\"></p>\
+       <p><input name=\"tags\" value=\"{}\" placeholder=\"Tags, separated by commas\" size=\"60\"></p>\
+       <p><textarea name=\"note\" placeholder=\"Notes\" rows=\"4\" cols=\"60\">{}</textarea></p>\
+       <button autofocus>Add</button></form>",
+      message,
+      field("url"),
+      field("title"),
+      field("tags"),
+      field("note")
+    ),
+  )
+}
+
+// Confirms that the bookmark was added, or was already there
+pub fn added(bookmark: &Bookmark, already_present: bool) -> Response {
+  let status = if already_present { "Already in the bookmarks" } else { "Added" };
+  html_response(
+    200,
+    status,
+    &format!("<p>{}:</p><ul>{}</ul><p><a href=\"/\">Bookmarks</a></p>", status, item(bookmark, None)),
+  )
+}
+
+// The stored copy of the page or, with text, its text. The stored copy is
+// sandboxed so that its scripts cannot reach the server.
+pub fn reader(url_store: &UrlStore, bookmark: &Bookmark, text: bool) -> Response {
+  let Ok(content) = url_store.get_article(&bookmark.href) else {
+    return html_response(
+      404,
+      &bookmark.title,
+      &format!(
+        "<p>No stored copy of <a href=\"{}\">{}</a>.</p>",
+        safe_href(bookmark),
+        escape_html(&bookmark.href)
+      ),
+    );
+  };
+  if !text {
+    return tiny_http::Response::from_string(content)
+      .with_header(header("Content-Type", "text/html; charset=utf-8"))
+      .with_header(header("Content-Security-Policy", "sandbox"));
+  }
+  let paragraphs: String = url_store
+    .get_text(&bookmark.href)
+    .unwrap_or_default()
+    .lines()
+    .filter(|line| !line.is_empty())
+    .map(|line| format!("<p>{}</p>\n", escape_html(line)))
+    .collect();
+  html_response(
+    200,
+    &bookmark.title,
+    &format!(
+      "<p><a href=\"/\">Bookmarks</a></p><h1><a href=\"{}\">{}</a></h1>\n{}",
+      safe_href(bookmark),
+      escape_html(&bookmark.title),
+      paragraphs
+    ),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::bookmark;
+
+  #[test]
+  fn links_to_the_web_pages_only() {
+    let href = |href: &str| safe_href(&bookmark(href, "2024-01-01"));
+    assert_eq!(href("https://example.com/?a=1&b=\"2\""), "https://example.com/?a=1&amp;b=&quot;2&quot;");
+    assert_eq!(href("http://example.com/"), "http://example.com/");
+    assert_eq!(href("javascript:alert(1)"), "#");
+    assert_eq!(href("JavaScript:alert(1)"), "#");
+    assert_eq!(href("data:text/html,<script>alert(1)</script>"), "#");
+  }
+
+  #[test]
+  fn redirects_to_the_local_paths_only() {
+    assert_eq!(local_path("/?q=rust"), "/?q=rust");
+    assert_eq!(local_path("/read/abc"), "/read/abc");
+    assert_eq!(local_path("//evil.example/"), "/");
+    assert_eq!(local_path("/\\evil.example/"), "/");
+    assert_eq!(local_path("http://evil.example/"), "/");
+    assert_eq!(local_path("evil.example"), "/");
+    assert_eq!(local_path(""), "/");
+  }
+}
+